@@ -0,0 +1,544 @@
+//! A DHT client that outlives a single lookup.
+//!
+//! The swarm is owned by a task spawned on the JS event loop, [`DhtClient`] only holds a channel
+//! to it. Lookups are tracked through [`Lookups`] so any number of them can be in flight at the
+//! same time, all sharing the same connections and routing table.
+
+use std::{collections::HashMap, num::NonZeroUsize, str::FromStr, time::Duration};
+
+use libp2p::{
+    futures::{
+        channel::{mpsc, oneshot},
+        future, select, StreamExt,
+    },
+    identify,
+    identity::Keypair,
+    kad::{self, QueryResult},
+    ping, relay,
+    swarm::{dial_opts::DialOpts, ConnectionId, DialError, NetworkBehaviour, SwarmEvent},
+    Multiaddr, PeerId, StreamProtocol, Swarm,
+};
+use libp2p_stream::OpenStreamError;
+use lp2p::{
+    address_policy::classify,
+    closest_peers::{ClosestPeersQueries, ClosestPeersResult},
+    lookup::{FoundAddress, LookupConfig, LookupResult, Lookups},
+    providers::{provider_key, FindProvidersResult, ProviderQueries},
+    swarm::{SwarmBuilder, SwarmConfig},
+    Error,
+};
+use wasm_bindgen::prelude::*;
+use web_sys::AbortSignal;
+
+use crate::{deadline, into_js_error, lookup::PeerLookup, stream::PeerStream};
+
+/// Connections are kept open while idle so later lookups skip dialing. A day outlasts any
+/// realistic page session while staying far below the ~24.8 day (2^31-1 ms) delay `setTimeout`
+/// supports, above which the idle timer would fire immediately.
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+enum Command {
+    Lookup {
+        peer_id: PeerId,
+        reply: oneshot::Sender<LookupResult>,
+    },
+    FindProviders {
+        key: String,
+        reply: oneshot::Sender<FindProvidersResult>,
+    },
+    ClosestPeers {
+        key: kad::RecordKey,
+        k: Option<NonZeroUsize>,
+        reply: oneshot::Sender<ClosestPeersResult>,
+    },
+    Dial {
+        peer_id: PeerId,
+        addrs: Vec<Multiaddr>,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const CLOSEST_PEER: &'static str = r#"
+/** A peer returned by `DhtClient.getClosestPeers`, with the addresses it is known under. */
+export interface ClosestPeer {
+    peerId: string;
+    addrs: string[];
+}
+"#;
+
+#[wasm_bindgen(typescript_custom_section)]
+const PROVIDER: &'static str = r#"
+/** A peer returned by `DhtClient.findProviders`, with the addresses it was looked up under. */
+export interface Provider {
+    peerId: string;
+    addrs: string[];
+}
+"#;
+
+/// Long-lived DHT client, construct it once and reuse it for every query.
+///
+/// Call `free()` once the client is no longer needed to close all of its connections.
+#[wasm_bindgen]
+pub struct DhtClient {
+    commands: mpsc::UnboundedSender<Command>,
+    streams: libp2p_stream::Control,
+}
+
+#[wasm_bindgen]
+impl DhtClient {
+    /// Creates a client and starts dialing `bootnodes`, which must include `/p2p/<peer-id>`.
+    ///
+    /// Each lookup gathers up to `quorum` valid records (1 by default) and returns the freshest
+    /// one. With `repair` set, that record is written back to the peers holding stale copies.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        bootnodes: Vec<String>,
+        quorum: Option<u32>,
+        repair: Option<bool>,
+    ) -> Result<DhtClient, JsValue> {
+        let config = LookupConfig {
+            quorum: quorum
+                .and_then(|quorum| NonZeroUsize::new(quorum as usize))
+                .unwrap_or(NonZeroUsize::MIN),
+            repair: repair.unwrap_or_default(),
+        };
+        Self::new_inner(bootnodes, config).map_err(into_js_error)
+    }
+
+    /// Looks up the addresses of `peer_id`, see `perform_query` for `timeout_ms` and `signal`.
+    #[wasm_bindgen(unchecked_return_type = "Promise<PeerLookup>")]
+    pub fn lookup(
+        &self,
+        peer_id: String,
+        timeout_ms: Option<u32>,
+        signal: Option<AbortSignal>,
+    ) -> js_sys::Promise {
+        let lookup = self.lookup_inner(peer_id);
+        wasm_bindgen_futures::future_to_promise(async move {
+            deadline::with_deadline(lookup, timeout_ms, signal)
+                .await
+                .map(|outcome| {
+                    serde_wasm_bindgen::to_value(&PeerLookup::from(outcome))
+                        .expect("Lookup results are valid JS values")
+                })
+                .map_err(into_js_error)
+        })
+    }
+
+    /// Finds the providers of the content key `key` and looks up their addresses, see
+    /// `perform_query` for `timeout_ms` and `signal`.
+    ///
+    /// Provider records only name the peer, its addresses are looked up like `lookup` does.
+    /// Providers which cannot be found are returned with no addresses.
+    #[wasm_bindgen(js_name = findProviders, unchecked_return_type = "Promise<Provider[]>")]
+    pub fn find_providers(
+        &self,
+        key: String,
+        timeout_ms: Option<u32>,
+        signal: Option<AbortSignal>,
+    ) -> js_sys::Promise {
+        let find = self.request(|reply| Command::FindProviders { key, reply });
+        let commands = self.commands.clone();
+        let resolve = async move {
+            let providers = find.await?;
+            let lookups = providers.into_iter().map(|peer_id| {
+                let commands = commands.clone();
+                async move {
+                    let lookup =
+                        send_command(&commands, |reply| Command::Lookup { peer_id, reply });
+                    let addresses = lookup
+                        .await
+                        .map(|outcome| outcome.addresses)
+                        .unwrap_or_else(|err| {
+                            tracing::warn!(
+                                "Failed to resolve the addresses of provider {peer_id}: {err}"
+                            );
+                            vec![]
+                        });
+                    (peer_id, addresses)
+                }
+            });
+            Ok(future::join_all(lookups).await)
+        };
+        wasm_bindgen_futures::future_to_promise(async move {
+            deadline::with_deadline(resolve, timeout_ms, signal)
+                .await
+                .map(|providers| {
+                    providers
+                        .iter()
+                        .map(|(peer_id, addresses)| provider_to_js(peer_id, addresses))
+                        .collect::<js_sys::Array>()
+                        .into()
+                })
+                .map_err(into_js_error)
+        })
+    }
+
+    /// Finds the `k` peers closest to `key` (20 at most, the replication factor by default), see
+    /// `perform_query` for `timeout_ms` and `signal`.
+    ///
+    /// A `key` that is a peer id stands for that peer, which discovers the peers around it even
+    /// if it has not published an address record. Any other `key` is taken as UTF-8.
+    #[wasm_bindgen(js_name = getClosestPeers, unchecked_return_type = "Promise<ClosestPeer[]>")]
+    pub fn get_closest_peers(
+        &self,
+        key: String,
+        k: Option<u32>,
+        timeout_ms: Option<u32>,
+        signal: Option<AbortSignal>,
+    ) -> js_sys::Promise {
+        let key = match PeerId::from_str(&key) {
+            Ok(peer_id) => kad::RecordKey::new(&peer_id.to_bytes()),
+            Err(_) => provider_key(&key),
+        };
+        let k = k.and_then(|k| NonZeroUsize::new(k as usize));
+        let find = self.request(move |reply| Command::ClosestPeers { key, k, reply });
+        wasm_bindgen_futures::future_to_promise(async move {
+            deadline::with_deadline(find, timeout_ms, signal)
+                .await
+                .map(|peers| {
+                    peers
+                        .iter()
+                        .map(closest_peer_to_js)
+                        .collect::<js_sys::Array>()
+                        .into()
+                })
+                .map_err(into_js_error)
+        })
+    }
+
+    /// Resolves `peer_id` through the DHT, dials it on its browser-dialable addresses and opens
+    /// a stream speaking `protocol` (e.g. `/my-app/1.0.0`), see `perform_query` for `timeout_ms`
+    /// and `signal`.
+    ///
+    /// The connection is part of this client's swarm, it stays open while streams or DHT
+    /// queries use it.
+    #[wasm_bindgen(js_name = openStream, unchecked_return_type = "Promise<PeerStream>")]
+    pub fn open_stream(
+        &self,
+        peer_id: String,
+        protocol: String,
+        timeout_ms: Option<u32>,
+        signal: Option<AbortSignal>,
+    ) -> js_sys::Promise {
+        let lookup = self.lookup_inner(peer_id);
+        let commands = self.commands.clone();
+        let mut streams = self.streams.clone();
+        let open = async move {
+            let protocol = StreamProtocol::try_from_owned(protocol.clone())
+                .map_err(|_| Error::InvalidProtocol(protocol))?;
+            let outcome = lookup.await?;
+            let peer_id = outcome.peer_id;
+
+            let addrs = outcome
+                .addresses
+                .into_iter()
+                .map(|address| address.addr)
+                .filter(|addr| classify(addr).transport.is_browser_dialable())
+                .collect::<Vec<_>>();
+            if addrs.is_empty() {
+                return Err(Error::NoDialableAddress(peer_id));
+            }
+            send_command(&commands, |reply| Command::Dial {
+                peer_id,
+                addrs,
+                reply,
+            })
+            .await?;
+
+            let stream = streams
+                .open_stream(peer_id, protocol)
+                .await
+                .map_err(|err| match err {
+                    OpenStreamError::UnsupportedProtocol(protocol) => {
+                        Error::UnsupportedProtocol(protocol.to_string())
+                    }
+                    OpenStreamError::Io(err) => Error::Stream(err),
+                    err => Error::Stream(std::io::Error::other(err)),
+                })?;
+            Ok(PeerStream::new(peer_id, stream))
+        };
+        wasm_bindgen_futures::future_to_promise(async move {
+            deadline::with_deadline(open, timeout_ms, signal)
+                .await
+                .map(JsValue::from)
+                .map_err(into_js_error)
+        })
+    }
+}
+
+/// Converts `peer` into a `ClosestPeer` object.
+fn closest_peer_to_js(peer: &kad::PeerInfo) -> JsValue {
+    let addrs = peer
+        .addrs
+        .iter()
+        .map(|addr| JsValue::from(addr.to_string()))
+        .collect::<js_sys::Array>();
+    let object = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&object, &"peerId".into(), &peer.peer_id.to_string().into());
+    let _ = js_sys::Reflect::set(&object, &"addrs".into(), &addrs);
+    object.into()
+}
+
+/// Converts a provider and its addresses into a `Provider` object.
+fn provider_to_js(peer_id: &PeerId, addresses: &[FoundAddress]) -> JsValue {
+    let addrs = addresses
+        .iter()
+        .map(|address| JsValue::from(address.addr.to_string()))
+        .collect::<js_sys::Array>();
+    let object = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&object, &"peerId".into(), &peer_id.to_string().into());
+    let _ = js_sys::Reflect::set(&object, &"addrs".into(), &addrs);
+    object.into()
+}
+
+impl DhtClient {
+    pub(crate) fn new_inner(bootnodes: Vec<String>, config: LookupConfig) -> Result<Self, Error> {
+        let bootnodes = bootnodes
+            .into_iter()
+            .map(|s| Multiaddr::from_str(&s))
+            .collect::<Result<Vec<Multiaddr>, _>>()?;
+
+        // This node is ephemeral so we don't care for the actual identity
+        // we can read it from the user selected account but to query the DHT it doesn't make a difference
+        let identity = Keypair::generate_ed25519();
+        let swarm = create_swarm(&identity, bootnodes)?;
+        let streams = swarm.behaviour().stream.new_control();
+
+        let (commands, receiver) = mpsc::unbounded();
+        let state = State {
+            swarm,
+            commands: receiver,
+            lookups: Lookups::new(config),
+            providers: ProviderQueries::default(),
+            closest_peers: ClosestPeersQueries::default(),
+            dials: HashMap::new(),
+        };
+        wasm_bindgen_futures::spawn_local(state.run());
+
+        Ok(Self { commands, streams })
+    }
+
+    /// Returns a future resolving to the lookup result, without borrowing the client.
+    pub(crate) fn lookup_inner(
+        &self,
+        peer_id: String,
+    ) -> impl std::future::Future<Output = LookupResult> + 'static {
+        let commands = self.commands.clone();
+        async move {
+            let peer_id = PeerId::from_str(&peer_id)?;
+            tracing::info!("Query: {}", peer_id);
+            send_command(&commands, |reply| Command::Lookup { peer_id, reply }).await
+        }
+    }
+
+    /// Returns a future resolving to the result of `command`, without borrowing the client.
+    fn request<T: 'static>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<T, Error>>) -> Command + 'static,
+    ) -> impl std::future::Future<Output = Result<T, Error>> + 'static {
+        let commands = self.commands.clone();
+        async move { send_command(&commands, command).await }
+    }
+}
+
+/// Sends the command built by `command` to the swarm task and waits for its reply.
+async fn send_command<T>(
+    commands: &mpsc::UnboundedSender<Command>,
+    command: impl FnOnce(oneshot::Sender<Result<T, Error>>) -> Command,
+) -> Result<T, Error> {
+    let (reply, response) = oneshot::channel();
+    commands
+        .unbounded_send(command(reply))
+        .map_err(|_| Error::Aborted)?;
+    // The sender is only dropped if the swarm task is gone
+    response.await.map_err(|_| Error::Aborted)?
+}
+
+fn create_swarm(identity: &Keypair, bootnodes: Vec<Multiaddr>) -> Result<Swarm<Behaviour>, Error> {
+    let mut builder = SwarmBuilder::new(
+        identity.to_owned(),
+        SwarmConfig {
+            idle_connection_timeout: IDLE_CONNECTION_TIMEOUT,
+            ..Default::default()
+        },
+    );
+    let behaviour = Behaviour::new(&mut builder, bootnodes.clone())?;
+    let mut swarm = builder.build(behaviour)?;
+
+    for node in bootnodes {
+        swarm.dial(node)?;
+    }
+
+    Ok(swarm)
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    ping: ping::Behaviour,
+    identify: identify::Behaviour,
+    kad: kad::Behaviour<kad::store::MemoryStore>,
+    stream: libp2p_stream::Behaviour,
+    /// Lets NATed peers be reached through the `/p2p-circuit` addresses in their records.
+    relay_client: relay::client::Behaviour,
+}
+
+impl Behaviour {
+    fn new(builder: &mut SwarmBuilder, bootnodes: Vec<Multiaddr>) -> Result<Self, Error> {
+        let ping = ping::Behaviour::new(ping::Config::default());
+        let identify = builder.identify();
+        let kad = builder.kad(
+            kad::store::MemoryStore::new(builder.local_peer_id()),
+            bootnodes,
+        )?;
+
+        Ok(Self {
+            ping,
+            identify,
+            kad,
+            stream: libp2p_stream::Behaviour::new(),
+            relay_client: builder.relay_client(),
+        })
+    }
+}
+
+struct State {
+    swarm: Swarm<Behaviour>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    lookups: Lookups,
+    providers: ProviderQueries,
+    closest_peers: ClosestPeersQueries,
+    /// Dials started by [`Command::Dial`], keyed by the connection they open.
+    dials: HashMap<ConnectionId, oneshot::Sender<Result<(), Error>>>,
+}
+
+impl State {
+    /// Drives the swarm until the owning [`DhtClient`] is dropped.
+    async fn run(mut self) {
+        loop {
+            select! {
+                command = self.commands.next() => match command {
+                    Some(command) => self.on_command(command),
+                    None => break,
+                },
+                event = self.swarm.select_next_some() => self.on_swarm_event(event),
+            }
+            let kad = &mut self.swarm.behaviour_mut().kad;
+            self.lookups.cancel_abandoned(kad);
+            self.providers.cancel_abandoned(kad);
+            self.closest_peers.cancel_abandoned(kad);
+            // A dial cannot be stopped, an abandoned one just opens an idle connection
+            self.dials.retain(|_, reply| !reply.is_canceled());
+        }
+        tracing::debug!("DHT client dropped, shutting down");
+    }
+
+    fn on_command(&mut self, command: Command) {
+        match command {
+            Command::Lookup { peer_id, reply } => {
+                self.lookups
+                    .start_with(&mut self.swarm.behaviour_mut().kad, &peer_id, reply);
+            }
+            Command::FindProviders { key, reply } => {
+                tracing::info!("Find providers: {key}");
+                self.providers.find(
+                    &mut self.swarm.behaviour_mut().kad,
+                    provider_key(&key),
+                    reply,
+                );
+            }
+            Command::ClosestPeers { key, k, reply } => {
+                tracing::info!("Get closest peers: {key:?}");
+                self.closest_peers
+                    .find(&mut self.swarm.behaviour_mut().kad, key, k, reply);
+            }
+            Command::Dial {
+                peer_id,
+                addrs,
+                reply,
+            } => {
+                tracing::info!("Dial {peer_id} on {addrs:?}");
+                let opts = DialOpts::peer_id(peer_id).addresses(addrs).build();
+                let connection_id = opts.connection_id();
+                match self.swarm.dial(opts) {
+                    Ok(()) => {
+                        self.dials.insert(connection_id, reply);
+                    }
+                    // Already connected or being dialed, opening a stream waits for the
+                    // connection
+                    Err(DialError::DialPeerConditionFalse(_)) => {
+                        let _ = reply.send(Ok(()));
+                    }
+                    Err(err) => {
+                        let _ = reply.send(Err(err.into()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_swarm_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::Behaviour(event) => self.on_behaviour_event(event),
+            SwarmEvent::ConnectionEstablished { connection_id, .. } => {
+                if let Some(reply) = self.dials.remove(&connection_id) {
+                    let _ = reply.send(Ok(()));
+                }
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
+                tracing::debug!("Failed to dial {peer_id:?}: {error}");
+                self.lookups.on_dial_failure(peer_id, &error);
+                if let Some(reply) = self.dials.remove(&connection_id) {
+                    let _ = reply.send(Err(error.into()));
+                }
+            }
+            _ => tracing::debug!("Received unhandled event: {event:?}"),
+        }
+    }
+
+    fn on_behaviour_event(&mut self, event: BehaviourEvent) {
+        match event {
+            BehaviourEvent::Kad(event) => match event {
+                kad::Event::OutboundQueryProgressed {
+                    id, result, stats, ..
+                } => match result {
+                    QueryResult::GetRecord(get_record_ok) => self.lookups.on_get_record(
+                        &mut self.swarm.behaviour_mut().kad,
+                        id,
+                        get_record_ok,
+                        stats,
+                    ),
+                    QueryResult::PutRecord(put_record_ok) => {
+                        self.lookups.on_put_record(id, put_record_ok)
+                    }
+                    QueryResult::GetProviders(get_providers_ok) => {
+                        self.providers.on_get_providers(id, get_providers_ok)
+                    }
+                    QueryResult::GetClosestPeers(get_closest_peers_ok) => {
+                        self.lookups.on_get_closest_peers(
+                            &mut self.swarm.behaviour_mut().kad,
+                            id,
+                            get_closest_peers_ok.clone(),
+                            stats,
+                        );
+                        self.closest_peers.on_get_closest_peers(
+                            &mut self.swarm.behaviour_mut().kad,
+                            id,
+                            get_closest_peers_ok,
+                        );
+                    }
+                    _ => tracing::debug!(
+                        "Received unhandled outbound query progress event: {result:?}"
+                    ),
+                },
+                _ => tracing::debug!("Received unhandled kademlia event: {event:?}"),
+            },
+            _ => tracing::debug!("Received unhandled behaviour event: {event:?}"),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Bounding the lifetime of a lookup by a timeout and an optional JS `AbortSignal`.
+
+use std::{future::Future, pin::pin, time::Duration};
+
+use futures_timer::Delay;
+use libp2p::futures::{
+    channel::oneshot,
+    future::{self, Either},
+};
+use lp2p::Error;
+use wasm_bindgen::{closure::Closure, JsCast};
+use web_sys::AbortSignal;
+
+/// Timeout applied when the caller does not provide one, in milliseconds.
+pub const DEFAULT_TIMEOUT_MS: u32 = 30_000;
+
+/// Runs `fut` until it completes, `timeout_ms` elapses or `signal` is aborted, whichever happens
+/// first. Dropping `fut` drops its reply channel, the `DhtClient` then finishes the abandoned
+/// query while its swarm and connections stay up for the next lookup.
+pub async fn with_deadline<T>(
+    fut: impl Future<Output = Result<T, Error>>,
+    timeout_ms: Option<u32>,
+    signal: Option<AbortSignal>,
+) -> Result<T, Error> {
+    let timeout = Delay::new(Duration::from_millis(
+        timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS).into(),
+    ));
+    let aborted = match signal {
+        Some(signal) => Either::Left(aborted(signal)),
+        None => Either::Right(future::pending()),
+    };
+
+    match future::select(pin!(fut), future::select(timeout, pin!(aborted))).await {
+        Either::Left((result, _)) => result,
+        Either::Right((Either::Left(_), _)) => Err(Error::Timeout),
+        Either::Right((Either::Right(_), _)) => Err(Error::Aborted),
+    }
+}
+
+/// Resolves once `signal` is aborted.
+async fn aborted(signal: AbortSignal) {
+    if signal.aborted() {
+        return;
+    }
+
+    let (tx, rx) = oneshot::channel();
+    let mut tx = Some(tx);
+    // Held until the future completes or is dropped, so the lookup finishing first removes it
+    let _listener = AbortListener::new(
+        signal,
+        Closure::new(move || {
+            if let Some(tx) = tx.take() {
+                let _ = tx.send(());
+            }
+        }),
+    );
+    let _ = rx.await;
+}
+
+/// An `abort` listener, removed from its signal when dropped.
+struct AbortListener {
+    signal: AbortSignal,
+    closure: Closure<dyn FnMut()>,
+}
+
+impl AbortListener {
+    fn new(signal: AbortSignal, closure: Closure<dyn FnMut()>) -> Self {
+        let _ = signal.add_event_listener_with_callback("abort", closure.as_ref().unchecked_ref());
+        Self { signal, closure }
+    }
+}
+
+impl Drop for AbortListener {
+    fn drop(&mut self) {
+        let _ = self
+            .signal
+            .remove_event_listener_with_callback("abort", self.closure.as_ref().unchecked_ref());
+    }
+}
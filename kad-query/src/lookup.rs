@@ -0,0 +1,79 @@
+//! The lookup result handed to JS.
+
+use lp2p::lookup::{AddressSource, FoundAddress, LookupOutcome};
+use serde::Serialize;
+use tsify_next::Tsify;
+
+/// The addresses found for a peer, along with how they were found.
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerLookup {
+    pub peer_id: String,
+    /// Confirmed addresses come first.
+    pub addresses: Vec<Address>,
+    /// The peer which stored the record, missing if the addresses come from routing tables.
+    #[tsify(optional)]
+    pub publisher: Option<String>,
+    /// When the record expires, in milliseconds since the Unix epoch like `Date.now()`. Missing
+    /// if the record came from its publisher, which keeps its own copy without expiry.
+    #[tsify(optional)]
+    pub expires_at: Option<f64>,
+    /// Number of requests sent to other peers while looking up the peer, including failed ones.
+    pub requests: u32,
+    /// How far the peer which returned the record is: `0` for the local store, `1` for a peer
+    /// in the local routing table and `2` for a peer discovered by the lookup, however many
+    /// referrals led to it. Missing if the addresses come from routing tables.
+    #[tsify(optional)]
+    pub source_distance: Option<u32>,
+    #[tsify(optional)]
+    pub elapsed_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Tsify)]
+pub struct Address {
+    pub addr: String,
+    /// Whether the peer confirmed the address to be reachable.
+    pub confirmed: bool,
+    pub source: Source,
+}
+
+/// Where an address comes from, `routing-table` addresses are unsigned and possibly stale.
+#[derive(Debug, Clone, Copy, Serialize, Tsify)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    Record,
+    RoutingTable,
+}
+
+impl From<LookupOutcome> for PeerLookup {
+    fn from(outcome: LookupOutcome) -> Self {
+        Self {
+            peer_id: outcome.peer_id.to_string(),
+            addresses: outcome.addresses.into_iter().map(Address::from).collect(),
+            publisher: outcome.publisher.map(|peer_id| peer_id.to_string()),
+            expires_at: outcome
+                .expires_in
+                .map(|expires_in| js_sys::Date::now() + expires_in.as_millis() as f64),
+            requests: outcome.stats.num_requests(),
+            source_distance: outcome.source_distance,
+            elapsed_ms: outcome
+                .stats
+                .duration()
+                .map(|elapsed| elapsed.as_millis() as f64),
+        }
+    }
+}
+
+impl From<FoundAddress> for Address {
+    fn from(address: FoundAddress) -> Self {
+        Self {
+            addr: address.addr.to_string(),
+            confirmed: address.confirmed,
+            source: match address.source {
+                AddressSource::Record => Source::Record,
+                AddressSource::RoutingTable => Source::RoutingTable,
+            },
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Streams to remote peers handed to JS.
+
+use std::rc::Rc;
+
+use libp2p::{
+    futures::{
+        io::{ReadHalf, WriteHalf},
+        lock::Mutex,
+        AsyncReadExt, AsyncWriteExt,
+    },
+    PeerId, Stream,
+};
+use lp2p::Error;
+use wasm_bindgen::prelude::*;
+
+use crate::into_js_error;
+
+/// Size of the buffer a single `read()` fills at most.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The reading side of a stream along with the buffer reads go through, allocated by the first
+/// `read()` and reused by the following ones.
+struct Reader {
+    stream: ReadHalf<Stream>,
+    buffer: Vec<u8>,
+}
+
+/// An open stream to a remote peer, see `DhtClient.openStream`.
+///
+/// Reads and writes may be in flight at the same time, concurrent reads (or writes) are served
+/// one after the other.
+#[wasm_bindgen]
+pub struct PeerStream {
+    peer_id: PeerId,
+    reader: Rc<Mutex<Reader>>,
+    writer: Rc<Mutex<WriteHalf<Stream>>>,
+}
+
+impl PeerStream {
+    pub(crate) fn new(peer_id: PeerId, stream: Stream) -> Self {
+        let (reader, writer) = stream.split();
+        Self {
+            peer_id,
+            reader: Rc::new(Mutex::new(Reader {
+                stream: reader,
+                buffer: vec![],
+            })),
+            writer: Rc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl PeerStream {
+    #[wasm_bindgen(getter, js_name = peerId)]
+    pub fn peer_id(&self) -> String {
+        self.peer_id.to_string()
+    }
+
+    /// Reads the next chunk of data, resolves to `undefined` once the remote closed its side.
+    #[wasm_bindgen(unchecked_return_type = "Promise<Uint8Array | undefined>")]
+    pub fn read(&self) -> js_sys::Promise {
+        let reader = self.reader.clone();
+        wasm_bindgen_futures::future_to_promise(async move {
+            let mut reader = reader.lock().await;
+            let Reader { stream, buffer } = &mut *reader;
+            buffer.resize(READ_BUFFER_SIZE, 0);
+            let read = stream.read(buffer).await;
+            match read.map_err(Error::Stream).map_err(into_js_error)? {
+                0 => Ok(JsValue::UNDEFINED),
+                n => Ok(js_sys::Uint8Array::from(&buffer[..n]).into()),
+            }
+        })
+    }
+
+    /// Writes all of `data`, resolving once it was handed to the connection.
+    #[wasm_bindgen(unchecked_return_type = "Promise<void>")]
+    pub fn write(&self, data: Vec<u8>) -> js_sys::Promise {
+        let writer = self.writer.clone();
+        wasm_bindgen_futures::future_to_promise(async move {
+            let mut writer = writer.lock().await;
+            let written = async {
+                writer.write_all(&data).await?;
+                writer.flush().await
+            };
+            written
+                .await
+                .map(|()| JsValue::UNDEFINED)
+                .map_err(|err| into_js_error(Error::Stream(err)))
+        })
+    }
+
+    /// Closes the writing side, the remote then reads the end of the stream. Data can still be
+    /// read until the remote closes its side as well.
+    #[wasm_bindgen(unchecked_return_type = "Promise<void>")]
+    pub fn close(&self) -> js_sys::Promise {
+        let writer = self.writer.clone();
+        wasm_bindgen_futures::future_to_promise(async move {
+            writer
+                .lock()
+                .await
+                .close()
+                .await
+                .map(|()| JsValue::UNDEFINED)
+                .map_err(|err| into_js_error(Error::Stream(err)))
+        })
+    }
+}
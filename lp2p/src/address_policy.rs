@@ -0,0 +1,209 @@
+//! Classification of multiaddrs, deciding which of our addresses are worth publishing.
+//!
+//! Remote resolvers can do nothing with loopback, container bridge or unspecified addresses, so
+//! by default only public and DNS addresses are published. Private ranges can be allowed for
+//! local testing.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use libp2p::{autonat, multiaddr::Protocol, Multiaddr};
+
+/// Who can reach an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// `0.0.0.0` or `::`, only meaningful to listen on.
+    Unspecified,
+    Loopback,
+    LinkLocal,
+    /// RFC 1918, carrier-grade NAT and IPv6 unique local ranges.
+    Private,
+    Public,
+    /// A DNS name, resolved by the dialer.
+    Dns,
+}
+
+/// How an address is dialed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Ws,
+    Wss,
+    Quic,
+    WebTransport,
+    WebRtcDirect,
+    Other,
+}
+
+impl Transport {
+    /// Whether browsers can dial the transport, which rules out plain TCP.
+    pub fn is_browser_dialable(self) -> bool {
+        match self {
+            Transport::Ws | Transport::Wss | Transport::WebTransport | Transport::WebRtcDirect => {
+                true
+            }
+            Transport::Tcp | Transport::Quic | Transport::Other => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Classification {
+    pub scope: Scope,
+    pub transport: Transport,
+}
+
+/// Classifies `addr` by its host and transport protocols.
+pub fn classify(addr: &Multiaddr) -> Classification {
+    let mut scope = Scope::Unspecified;
+    let mut transport = Transport::Other;
+    let mut tls = false;
+
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::Ip4(ip) => scope = ipv4_scope(ip),
+            Protocol::Ip6(ip) => scope = ipv6_scope(ip),
+            Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_) => {
+                scope = Scope::Dns
+            }
+            Protocol::Tcp(_) if transport == Transport::Other => transport = Transport::Tcp,
+            Protocol::Tls => tls = true,
+            // `/tls/ws` is the current spelling of the deprecated `/wss`
+            Protocol::Ws(_) if tls => transport = Transport::Wss,
+            Protocol::Ws(_) => transport = Transport::Ws,
+            Protocol::Wss(_) => transport = Transport::Wss,
+            Protocol::QuicV1 => transport = Transport::Quic,
+            Protocol::WebTransport => transport = Transport::WebTransport,
+            Protocol::WebRTCDirect => transport = Transport::WebRtcDirect,
+            _ => {}
+        }
+    }
+
+    Classification { scope, transport }
+}
+
+fn ipv4_scope(ip: Ipv4Addr) -> Scope {
+    let [a, b, ..] = ip.octets();
+    if ip.is_unspecified() {
+        Scope::Unspecified
+    } else if ip.is_loopback() {
+        Scope::Loopback
+    } else if ip.is_link_local() {
+        Scope::LinkLocal
+    } else if ip.is_private() || (a == 100 && (b & 0b1100_0000) == 64) {
+        Scope::Private
+    } else {
+        Scope::Public
+    }
+}
+
+fn ipv6_scope(ip: Ipv6Addr) -> Scope {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return ipv4_scope(ip);
+    }
+
+    let first = ip.segments()[0];
+    if ip.is_unspecified() {
+        Scope::Unspecified
+    } else if ip.is_loopback() {
+        Scope::Loopback
+    } else if (first & 0xffc0) == 0xfe80 {
+        Scope::LinkLocal
+    } else if (first & 0xfe00) == 0xfc00 {
+        Scope::Private
+    } else {
+        Scope::Public
+    }
+}
+
+/// Decides which addresses may be published.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AddressPolicy {
+    /// Also allow loopback, link-local and private addresses, for local testing.
+    pub allow_private: bool,
+}
+
+impl AddressPolicy {
+    pub fn allows(&self, addr: &Multiaddr) -> bool {
+        match classify(addr).scope {
+            Scope::Public | Scope::Dns => true,
+            Scope::Loopback | Scope::LinkLocal | Scope::Private => self.allow_private,
+            Scope::Unspecified => false,
+        }
+    }
+
+    /// AutoNAT configuration matching the policy, so private addresses can only be confirmed
+    /// when they are allowed.
+    pub fn autonat_config(&self) -> autonat::Config {
+        autonat::Config {
+            only_global_ips: !self.allow_private,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classified(addr: &str) -> (Scope, Transport) {
+        let Classification { scope, transport } = classify(&addr.parse().unwrap());
+        (scope, transport)
+    }
+
+    #[test]
+    fn scopes() {
+        let cases = [
+            ("/ip4/0.0.0.0/tcp/64001", Scope::Unspecified),
+            ("/ip4/127.0.0.1/tcp/64001", Scope::Loopback),
+            ("/ip4/169.254.1.1/tcp/64001", Scope::LinkLocal),
+            ("/ip4/10.0.0.1/tcp/64001", Scope::Private),
+            ("/ip4/172.17.0.2/tcp/64001", Scope::Private),
+            ("/ip4/192.168.1.1/tcp/64001", Scope::Private),
+            ("/ip4/100.64.0.1/tcp/64001", Scope::Private),
+            ("/ip4/100.128.0.1/tcp/64001", Scope::Public),
+            ("/ip4/8.8.8.8/tcp/64001", Scope::Public),
+            ("/ip6/::/tcp/64001", Scope::Unspecified),
+            ("/ip6/::1/tcp/64001", Scope::Loopback),
+            ("/ip6/fe80::1/tcp/64001", Scope::LinkLocal),
+            ("/ip6/fd00::1/tcp/64001", Scope::Private),
+            ("/ip6/::ffff:10.0.0.1/tcp/64001", Scope::Private),
+            ("/ip6/2001:4860::8888/tcp/64001", Scope::Public),
+            ("/dns4/example.com/tcp/64001", Scope::Dns),
+        ];
+        for (addr, scope) in cases {
+            assert_eq!(classified(addr).0, scope, "{addr}");
+        }
+    }
+
+    #[test]
+    fn transports() {
+        let cases = [
+            ("/ip4/8.8.8.8/tcp/64001", Transport::Tcp),
+            ("/ip4/8.8.8.8/tcp/64002/ws", Transport::Ws),
+            ("/ip4/8.8.8.8/tcp/443/wss", Transport::Wss),
+            ("/dns4/example.com/tcp/443/tls/ws", Transport::Wss),
+            ("/ip4/8.8.8.8/udp/64003/quic-v1", Transport::Quic),
+        ];
+        for (addr, transport) in cases {
+            assert_eq!(classified(addr).1, transport, "{addr}");
+        }
+    }
+
+    #[test]
+    fn policy() {
+        let public = "/ip4/8.8.8.8/tcp/64001".parse().unwrap();
+        let private = "/ip4/192.168.1.1/tcp/64001".parse().unwrap();
+        let unspecified = "/ip4/0.0.0.0/tcp/64001".parse().unwrap();
+
+        let strict = AddressPolicy::default();
+        assert!(strict.allows(&public));
+        assert!(!strict.allows(&private));
+        assert!(!strict.allows(&unspecified));
+
+        let local = AddressPolicy {
+            allow_private: true,
+        };
+        assert!(local.allows(&private));
+        assert!(!local.allows(&unspecified));
+    }
+}
@@ -0,0 +1,53 @@
+//! Loading and generation of the certificate of the webrtc-direct listener.
+//!
+//! Browsers authenticate a webrtc-direct listener by the hash of its certificate, which is part of
+//! the listen address (`/certhash/...`). The certificate is therefore stored alongside the
+//! identity so bootnode addresses stay valid across restarts.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use libp2p_webrtc::tokio::{certificate, Certificate};
+
+use crate::keypair::write_private;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CertificateError {
+    #[error("failed to access certificate file {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("certificate file {} does not hold a valid certificate: {source}", path.display())]
+    Decode {
+        path: PathBuf,
+        source: certificate::Error,
+    },
+}
+
+/// Reads the PEM encoded certificate stored at `path`, generating and saving a new one if the
+/// file does not exist yet.
+pub fn load_or_generate(path: &Path) -> Result<Certificate, CertificateError> {
+    let io_error = |source| CertificateError::Io {
+        path: path.to_owned(),
+        source,
+    };
+
+    match fs::read_to_string(path) {
+        Ok(pem) => Certificate::from_pem(&pem).map_err(|source| CertificateError::Decode {
+            path: path.to_owned(),
+            source,
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let certificate = generate();
+            write_private(path, certificate.serialize_pem().as_bytes()).map_err(io_error)?;
+            tracing::info!("Generated new certificate at {}", path.display());
+            Ok(certificate)
+        }
+        Err(err) => Err(io_error(err)),
+    }
+}
+
+/// Generates an ephemeral certificate, its hash changes with every call.
+pub fn generate() -> Certificate {
+    Certificate::generate(&mut rand::thread_rng()).expect("certificate generation never fails")
+}
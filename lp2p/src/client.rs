@@ -1,14 +1,23 @@
-use std::time::Duration;
+use std::process::ExitCode;
 
 use clap::Parser;
 use libp2p::{
-    core,
+    autonat, dcutr,
     futures::StreamExt,
     identify,
-    identity::{self, Keypair},
-    noise,
-    swarm::{self, NetworkBehaviour, SwarmEvent},
-    tcp, websocket, yamux, Multiaddr, Swarm, Transport,
+    identity::Keypair,
+    kad,
+    multiaddr::Protocol,
+    relay,
+    swarm::{NetworkBehaviour, SwarmEvent},
+    Multiaddr, Swarm,
+};
+use lp2p::{
+    address_policy::AddressPolicy,
+    keypair::IdentityArgs,
+    publish::{Publisher, DEFAULT_REPUBLISH_INTERVAL},
+    swarm::{SwarmBuilder, SwarmConfig},
+    Error,
 };
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
@@ -16,37 +25,79 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 #[derive(Clone, Debug, clap::Parser)]
 struct App {
     bootnode: Multiaddr,
+
+    #[arg(short = 'l', value_delimiter = ',')]
+    listen_addrs: Vec<Multiaddr>,
+
+    /// Publish a signed record of our reachable addresses to the DHT and keep it up to date.
+    #[arg(long)]
+    publish: bool,
+
+    /// Also publish loopback, link-local and private listen addresses, for local testing.
+    #[arg(long)]
+    allow_private: bool,
+
+    /// Reserve a slot on the bootnode's relay and listen on the resulting `/p2p-circuit`
+    /// address, for peers behind a NAT.
+    #[arg(long)]
+    relay: bool,
+
+    #[command(flatten)]
+    identity: IdentityArgs,
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::DEBUG))
         .init();
 
     let app = App::parse();
 
-    let identity = identity::Keypair::generate_ed25519();
-    let local_peer_id = identity.public().to_peer_id();
-    tracing::info!("Local peer id {}", local_peer_id);
-    let mut swarm = create_swarm(&identity);
+    match run(app).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            tracing::error!("{err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
 
-    // let ifaces = [/*"/ip4/0.0.0.0/tcp/0", */ "/ip4/0.0.0.0/tcp/0/ws"];
-    // ifaces
-    //     .into_iter()
-    //     .map(str::parse::<Multiaddr>)
-    //     .map(Result::unwrap)
-    //     .for_each(|maddr| {
-    //         swarm.listen_on(maddr).unwrap();
-    //     });
+async fn run(app: App) -> Result<(), Error> {
+    let identity = app.identity.keypair()?;
+    let policy = AddressPolicy {
+        allow_private: app.allow_private,
+    };
+    let mut swarm = create_swarm(&identity, vec![app.bootnode.clone()], policy)?;
 
-    swarm.dial(app.bootnode).unwrap();
+    for addr in app.listen_addrs {
+        swarm.listen_on(addr)?;
+    }
+    if app.relay {
+        // Dials the bootnode itself, a concurrent dial would make the relay client drop the
+        // reservation request. The circuit address is published once the reservation is accepted
+        swarm.listen_on(app.bootnode.with(Protocol::P2pCircuit))?;
+    } else {
+        swarm.dial(app.bootnode)?;
+    }
 
-    let mut state = State { swarm };
+    let mut state = State {
+        swarm,
+        publisher: app.publish.then(|| Publisher::new(identity, policy)),
+    };
+
+    let mut republish = tokio::time::interval(DEFAULT_REPUBLISH_INTERVAL);
+    // The first tick completes immediately, the record is published once we are listening
+    republish.tick().await;
 
     loop {
         tokio::select! {
-            event = state.swarm.select_next_some() => state.on_swarm_event(event)
+            event = state.swarm.select_next_some() => state.on_swarm_event(event),
+            _ = republish.tick() => {
+                if let Some(publisher) = &mut state.publisher {
+                    publisher.republish(&mut state.swarm.behaviour_mut().kad);
+                }
+            }
         }
     }
 }
@@ -54,21 +105,41 @@ async fn main() {
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     identify: identify::Behaviour,
+    kad: kad::Behaviour<kad::store::MemoryStore>,
+    autonat: autonat::Behaviour,
+    relay_client: relay::client::Behaviour,
+    /// Upgrades relayed connections to direct ones by hole punching.
+    dcutr: dcutr::Behaviour,
 }
 
 impl Behaviour {
-    fn new(keypair: Keypair) -> Self {
-        let identify = identify::Behaviour::new(identify::Config::new(
-            "/polka-test/identify/1.0.0".to_string(),
-            keypair.public(),
-        ));
-
-        Self { identify }
+    fn new(
+        builder: &mut SwarmBuilder,
+        bootnodes: Vec<Multiaddr>,
+        policy: AddressPolicy,
+    ) -> Result<Self, Error> {
+        let identify = builder.identify();
+        let kad = builder.kad(
+            kad::store::MemoryStore::new(builder.local_peer_id()),
+            bootnodes.clone(),
+        )?;
+        let autonat = builder.autonat(policy.autonat_config(), &bootnodes);
+        let relay_client = builder.relay_client();
+
+        Ok(Self {
+            identify,
+            kad,
+            autonat,
+            relay_client,
+            dcutr: dcutr::Behaviour::new(builder.local_peer_id()),
+        })
     }
 }
 
 struct State {
     swarm: Swarm<Behaviour>,
+    /// Only set when running with `--publish`.
+    publisher: Option<Publisher>,
 }
 
 impl State {
@@ -76,9 +147,22 @@ impl State {
         match event {
             SwarmEvent::NewListenAddr { address, .. } => {
                 tracing::debug!("New listen address: {address}");
+                self.publish_own_record();
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                tracing::debug!("Expired listen address: {address}");
+                self.publish_own_record();
+            }
+            SwarmEvent::NewExternalAddrCandidate { address } => {
+                tracing::debug!("Local external address candidate: {address}")
             }
             SwarmEvent::ExternalAddrConfirmed { address } => {
-                tracing::debug!("Local external address confirmed: {address}")
+                tracing::debug!("Local external address confirmed: {address}");
+                self.publish_own_record();
+            }
+            SwarmEvent::ExternalAddrExpired { address } => {
+                tracing::debug!("Local external address expired: {address}");
+                self.publish_own_record();
             }
             SwarmEvent::NewExternalAddrOfPeer { peer_id, address } => {
                 tracing::debug!("External address confirmed: {address} for {peer_id}")
@@ -93,32 +177,72 @@ impl State {
             BehaviourEvent::Identify(event) => {
                 tracing::debug!("Received unhandled identify event: {event:?}")
             }
+            BehaviourEvent::Autonat(event) => {
+                tracing::debug!("Received unhandled autonat event: {event:?}")
+            }
+            BehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted {
+                relay_peer_id,
+                renewal: false,
+                ..
+            }) => tracing::info!("Reserved a relay slot on {relay_peer_id}"),
+            BehaviourEvent::RelayClient(event) => {
+                tracing::debug!("Received unhandled relay client event: {event:?}")
+            }
+            BehaviourEvent::Dcutr(dcutr::Event {
+                remote_peer_id,
+                result,
+            }) => match result {
+                Ok(_) => tracing::info!("Direct connection to {remote_peer_id} established"),
+                Err(err) => {
+                    tracing::warn!("Connection to {remote_peer_id} stays relayed: {err}")
+                }
+            },
+            BehaviourEvent::Kad(event) => match event {
+                kad::Event::OutboundQueryProgressed {
+                    result: kad::QueryResult::PutRecord(result),
+                    ..
+                } => match result {
+                    Ok(ok) => tracing::info!("Successful PutRecord: {ok:?}"),
+                    Err(err) => tracing::error!("Failed PutRecord: {err:?}"),
+                },
+                _ => tracing::debug!("Received unhandled kademlia event: {event:?}"),
+            },
         }
     }
+
+    /// Publishes our confirmed external and allowed listen addresses if they changed and
+    /// `--publish` was given.
+    fn publish_own_record(&mut self) {
+        let Some(publisher) = &mut self.publisher else {
+            return;
+        };
+
+        let listen_addrs = self.swarm.listeners().cloned().collect();
+        let external_addrs = self.swarm.external_addresses().cloned().collect();
+        publisher.update(
+            &mut self.swarm.behaviour_mut().kad,
+            listen_addrs,
+            external_addrs,
+        );
+    }
 }
 
-fn create_swarm(identity: &Keypair) -> Swarm<Behaviour> {
-    let local_peer_id = identity.public().to_peer_id();
-    tracing::info!("Local peer id: {local_peer_id}");
-
-    let noise_config = noise::Config::new(&identity).unwrap(); // TODO: proper error handling
-    let muxer_config = yamux::Config::default();
-
-    let tcp_config = tcp::Config::new();
-    let tcp_transport = tcp::tokio::Transport::new(tcp_config.clone());
-
-    let ws = websocket::WsConfig::new(tcp::tokio::Transport::new(tcp_config));
-    let tcp_ws_transport = tcp_transport
-        .or_transport(ws)
-        .upgrade(core::upgrade::Version::V1Lazy)
-        .authenticate(noise_config)
-        .multiplex(muxer_config)
-        .boxed();
-
-    Swarm::new(
-        tcp_ws_transport,
-        Behaviour::new(identity.to_owned()),
-        local_peer_id,
-        swarm::Config::with_tokio_executor().with_idle_connection_timeout(Duration::from_secs(10)),
-    )
+fn create_swarm(
+    identity: &Keypair,
+    bootnodes: Vec<Multiaddr>,
+    policy: AddressPolicy,
+) -> Result<Swarm<Behaviour>, Error> {
+    let mut kad_config = kad::Config::new(kad::PROTOCOL_NAME);
+    // `Publisher` signs a fresh record on every republish, see `RecordsConfig::apply`
+    kad_config.set_publication_interval(None);
+
+    let mut builder = SwarmBuilder::new(
+        identity.to_owned(),
+        SwarmConfig {
+            kad: kad_config,
+            ..Default::default()
+        },
+    );
+    let behaviour = Behaviour::new(&mut builder, bootnodes, policy)?;
+    builder.build(behaviour)
 }
@@ -0,0 +1,104 @@
+//! Bookkeeping for `GetClosestPeers` walks.
+//!
+//! The walk returns the peers closest to a key along with the addresses they are known under,
+//! which tells where records and provider records for the key end up.
+
+use std::{collections::HashMap, num::NonZeroUsize};
+
+use libp2p::{
+    futures::channel::oneshot,
+    kad::{self, store::RecordStore, GetClosestPeersError, GetClosestPeersResult, QueryId},
+};
+
+use crate::{finish_query, routing_table_addresses, Error};
+
+pub type ClosestPeersResult = Result<Vec<kad::PeerInfo>, Error>;
+
+/// In-flight `GetClosestPeers` queries, keyed by the query driving them.
+#[derive(Default)]
+pub struct ClosestPeersQueries {
+    pending: HashMap<QueryId, oneshot::Sender<ClosestPeersResult>>,
+}
+
+impl ClosestPeersQueries {
+    /// Looks up the `k` peers closest to `key`, they are sent to `reply` once the walk is over.
+    ///
+    /// `k` defaults to the replication factor and is capped at [`kad::K_VALUE`].
+    pub fn find<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        key: kad::RecordKey,
+        k: Option<NonZeroUsize>,
+        reply: oneshot::Sender<ClosestPeersResult>,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        let query_id = match k {
+            Some(k) => kad.get_n_closest_peers(key.to_vec(), k),
+            None => kad.get_closest_peers(key.to_vec()),
+        };
+        tracing::debug!("Sent GetClosestPeers request: {query_id:?}");
+        self.pending.insert(query_id, reply);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Handles a `GetClosestPeers` result, ignoring queries not started through
+    /// [`ClosestPeersQueries`].
+    ///
+    /// The walk only lists the addresses it learned from other peers, the addresses of peers
+    /// which were already in the local routing table are taken from there.
+    pub fn on_get_closest_peers<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        query_id: QueryId,
+        result: GetClosestPeersResult,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        let Some(reply) = self.pending.remove(&query_id) else {
+            return;
+        };
+
+        let result = match result {
+            Ok(ok) => Ok(ok.peers),
+            Err(GetClosestPeersError::Timeout { peers, .. }) => {
+                tracing::error!("GetClosestPeers timed out with {} peers", peers.len());
+                // Peers found before the timeout are still worth returning
+                if peers.is_empty() {
+                    Err(Error::Timeout)
+                } else {
+                    Ok(peers)
+                }
+            }
+        };
+        let result = result.map(|peers| {
+            peers
+                .into_iter()
+                .map(|mut peer| {
+                    if peer.addrs.is_empty() {
+                        peer.addrs = routing_table_addresses(kad, peer.peer_id);
+                    }
+                    peer
+                })
+                .collect()
+        });
+        let _ = reply.send(result);
+    }
+
+    /// Stops the walks whose callers are no longer waiting for a result.
+    pub fn cancel_abandoned<S>(&mut self, kad: &mut kad::Behaviour<S>)
+    where
+        S: RecordStore + Send + 'static,
+    {
+        self.pending.retain(|query_id, reply| {
+            if reply.is_canceled() {
+                finish_query(kad, query_id);
+                return false;
+            }
+            true
+        });
+    }
+}
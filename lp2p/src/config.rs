@@ -0,0 +1,150 @@
+//! Server configuration, read from a TOML file and overridden by command line flags.
+//!
+//! ```toml
+//! [records]
+//! record_ttl = "48h"
+//! replication_factor = 20
+//! republish_interval = "12h"
+//! replication_interval = "1h"
+//! provider_ttl = "48h"
+//! drop_unreachable_after = "1h"
+//! ```
+
+use std::{
+    fs, io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use libp2p::kad;
+use serde::Deserialize;
+
+use crate::publish::DEFAULT_REPUBLISH_INTERVAL;
+
+/// Lifetime of records and provider records, matching the libp2p default.
+pub const DEFAULT_RECORD_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+/// How often stored records are replicated to the closest peers, matching the libp2p default.
+pub const DEFAULT_REPLICATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long a peer may stay unreachable before its address record is dropped.
+pub const DEFAULT_DROP_UNREACHABLE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("config file {} is invalid: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub records: RecordsConfig,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+/// Record lifetime settings, unset values fall back to the defaults of this module.
+///
+/// Durations are written like `90s`, `15m` or `48h`.
+#[derive(Debug, Clone, Default, clap::Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordsConfig {
+    /// Lifetime of stored records [default: 48h].
+    #[arg(long, value_parser = humantime::parse_duration)]
+    #[serde(with = "humantime_serde")]
+    pub record_ttl: Option<Duration>,
+
+    /// Number of peers each record is replicated to [default: 20].
+    #[arg(long)]
+    pub replication_factor: Option<NonZeroUsize>,
+
+    /// How often our own address record is signed and published again [default: 12h].
+    #[arg(long, value_parser = humantime::parse_duration)]
+    #[serde(with = "humantime_serde")]
+    pub republish_interval: Option<Duration>,
+
+    /// How often stored records are replicated to the closest peers [default: 1h].
+    #[arg(long, value_parser = humantime::parse_duration)]
+    #[serde(with = "humantime_serde")]
+    pub replication_interval: Option<Duration>,
+
+    /// Lifetime of provider records [default: 48h].
+    #[arg(long, value_parser = humantime::parse_duration)]
+    #[serde(with = "humantime_serde")]
+    pub provider_ttl: Option<Duration>,
+
+    /// Drop the address record of a peer that could not be reached for this long [default: 1h].
+    #[arg(long, value_parser = humantime::parse_duration)]
+    #[serde(with = "humantime_serde")]
+    pub drop_unreachable_after: Option<Duration>,
+}
+
+impl RecordsConfig {
+    /// Returns `self` with its unset values taken from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            record_ttl: self.record_ttl.or(fallback.record_ttl),
+            replication_factor: self.replication_factor.or(fallback.replication_factor),
+            republish_interval: self.republish_interval.or(fallback.republish_interval),
+            replication_interval: self.replication_interval.or(fallback.replication_interval),
+            provider_ttl: self.provider_ttl.or(fallback.provider_ttl),
+            drop_unreachable_after: self
+                .drop_unreachable_after
+                .or(fallback.drop_unreachable_after),
+        }
+    }
+
+    pub fn record_ttl(&self) -> Duration {
+        self.record_ttl.unwrap_or(DEFAULT_RECORD_TTL)
+    }
+
+    pub fn republish_interval(&self) -> Duration {
+        self.republish_interval
+            .unwrap_or(DEFAULT_REPUBLISH_INTERVAL)
+    }
+
+    pub fn drop_unreachable_after(&self) -> Duration {
+        self.drop_unreachable_after
+            .unwrap_or(DEFAULT_DROP_UNREACHABLE_AFTER)
+    }
+
+    /// Applies the record settings to `config`.
+    ///
+    /// Kademlia's own publication job is disabled, since it would keep publishing the first
+    /// signature of our record, [`crate::publish::Publisher`] signs a fresh one instead.
+    pub fn apply(&self, config: &mut kad::Config) {
+        if self.republish_interval() >= self.record_ttl() {
+            tracing::warn!(
+                "Republish interval {:?} is not shorter than the record TTL {:?}",
+                self.republish_interval(),
+                self.record_ttl()
+            );
+        }
+
+        config
+            .set_record_ttl(Some(self.record_ttl()))
+            .set_replication_factor(self.replication_factor.unwrap_or(kad::K_VALUE))
+            .set_publication_interval(None)
+            .set_replication_interval(Some(
+                self.replication_interval
+                    .unwrap_or(DEFAULT_REPLICATION_INTERVAL),
+            ))
+            .set_provider_record_ttl(Some(self.provider_ttl.unwrap_or(DEFAULT_RECORD_TTL)));
+    }
+}
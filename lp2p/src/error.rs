@@ -0,0 +1,171 @@
+//! The error type surfaced by the library, the binaries and the wasm bindings.
+
+use std::io;
+
+use libp2p::{identity::ParseError, kad, multiaddr, noise, swarm::DialError, Multiaddr, PeerId};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    certificate::CertificateError, config::ConfigError, keypair::KeypairError, store::StoreError,
+    tls::TlsError,
+};
+use crate::{record::RecordError, validation::ValidationError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid multiaddr: {0}")]
+    InvalidMultiaddr(#[from] multiaddr::Error),
+    #[error("invalid peer id: {0}")]
+    InvalidPeerId(#[from] ParseError),
+    #[error("multiaddr {0} is missing a /p2p segment")]
+    MissingPeerId(Multiaddr),
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[error("invalid protocol name {0:?}, it must start with '/'")]
+    InvalidProtocol(String),
+    #[error("failed to decode record: {0}")]
+    Decode(#[from] RecordError),
+    #[error("servers would not store the record: {0}")]
+    InvalidRecord(#[from] ValidationError),
+    #[error("the query timed out")]
+    Timeout,
+    #[error("the query was aborted")]
+    Aborted,
+    #[error("no record was found")]
+    NoRecord,
+    #[error("the query did not reach its quorum")]
+    QuorumFailed,
+    #[error("failed to set up noise: {0}")]
+    Noise(#[from] noise::Error),
+    #[error("no transport is enabled")]
+    NoTransport,
+    #[error("failed to dial: {0}")]
+    Dial(#[from] DialError),
+    #[error("none of the addresses of {0} can be dialed")]
+    NoDialableAddress(PeerId),
+    #[error("the remote does not support protocol {0}")]
+    UnsupportedProtocol(String),
+    #[error("stream failed: {0}")]
+    Stream(io::Error),
+    #[error("failed to listen: {0}")]
+    Listen(#[from] libp2p::TransportError<io::Error>),
+    #[error("failed to store record locally: {0}")]
+    LocalStore(#[from] kad::store::Error),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Keypair(#[from] KeypairError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Certificate(#[from] CertificateError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("failed to read input: {0}")]
+    Input(io::Error),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("failed to install a signal handler: {0}")]
+    Signal(io::Error),
+}
+
+impl Error {
+    /// Stable, machine readable name of the error category, exposed to JS as `error.code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidMultiaddr(_) => "invalid-multiaddr",
+            Error::InvalidPeerId(_) => "invalid-peer-id",
+            Error::MissingPeerId(_) => "missing-peer-id",
+            Error::InvalidKey(_) => "invalid-key",
+            Error::InvalidProtocol(_) => "invalid-protocol",
+            Error::Decode(_) => "decode",
+            Error::InvalidRecord(_) => "invalid-record",
+            Error::Timeout => "timeout",
+            Error::Aborted => "aborted",
+            Error::NoRecord => "no-record",
+            Error::QuorumFailed => "quorum-failed",
+            Error::Noise(_) | Error::NoTransport | Error::Dial(_) | Error::Listen(_) => "transport",
+            Error::NoDialableAddress(_) => "no-dialable-address",
+            Error::UnsupportedProtocol(_) => "unsupported-protocol",
+            Error::Stream(_) => "stream",
+            Error::LocalStore(_) => "store",
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Keypair(_) => "identity",
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Certificate(_) => "certificate",
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Tls(_) => "tls",
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Store(_) => "store",
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Config(_) => "config",
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Input(_) => "input",
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Signal(_) => "signal",
+        }
+    }
+
+    /// Process exit code for the binaries, `2` is shared with clap's usage errors.
+    ///
+    /// The codes are listed in the README, scripts rely on them so they must never change.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::InvalidMultiaddr(_)
+            | Error::InvalidPeerId(_)
+            | Error::MissingPeerId(_)
+            | Error::InvalidKey(_)
+            | Error::InvalidProtocol(_) => 2,
+            Error::Decode(_) | Error::InvalidRecord(_) => 3,
+            Error::Timeout | Error::Aborted => 4,
+            Error::NoRecord => 5,
+            Error::QuorumFailed => 9,
+            Error::Noise(_)
+            | Error::NoTransport
+            | Error::Dial(_)
+            | Error::Listen(_)
+            | Error::NoDialableAddress(_)
+            | Error::UnsupportedProtocol(_)
+            | Error::Stream(_) => 6,
+            Error::LocalStore(_) => 8,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Keypair(_) => 7,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Certificate(_) => 11,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Tls(_) => 12,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Store(_) => 8,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Config(_) => 10,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Input(_) => 2,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Signal(_) => 13,
+        }
+    }
+}
+
+impl From<kad::GetRecordError> for Error {
+    fn from(err: kad::GetRecordError) -> Self {
+        match err {
+            kad::GetRecordError::Timeout { .. } => Error::Timeout,
+            kad::GetRecordError::NotFound { .. } => Error::NoRecord,
+            kad::GetRecordError::QuorumFailed { .. } => Error::QuorumFailed,
+        }
+    }
+}
+
+impl From<kad::PutRecordError> for Error {
+    fn from(err: kad::PutRecordError) -> Self {
+        match err {
+            kad::PutRecordError::Timeout { .. } => Error::Timeout,
+            kad::PutRecordError::QuorumFailed { .. } => Error::QuorumFailed,
+        }
+    }
+}
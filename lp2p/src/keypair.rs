@@ -0,0 +1,161 @@
+//! Loading and generation of the node's identity keypair.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use libp2p::identity::{DecodingError, Keypair};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeypairError {
+    #[error("failed to access key file {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("key file {} does not hold a valid keypair: {source}", path.display())]
+    Decode {
+        path: PathBuf,
+        source: DecodingError,
+    },
+}
+
+/// Command line arguments selecting the node's identity, shared by all binaries.
+#[derive(Debug, Clone, clap::Args)]
+pub struct IdentityArgs {
+    /// Protobuf-encoded keypair file, a new ed25519 keypair is written to it if it does not exist.
+    #[arg(long)]
+    pub identity: Option<PathBuf>,
+
+    /// Derive the keypair from a fixed seed, only meant for tests.
+    #[arg(long, conflicts_with = "identity")]
+    pub identity_seed: Option<u8>,
+}
+
+impl IdentityArgs {
+    /// Returns the selected keypair, falling back to a fresh ephemeral one.
+    pub fn keypair(&self) -> Result<Keypair, KeypairError> {
+        if let Some(path) = &self.identity {
+            return load_or_generate(path);
+        }
+        if let Some(seed) = self.identity_seed {
+            return Ok(from_seed(seed));
+        }
+        Ok(Keypair::generate_ed25519())
+    }
+}
+
+/// Reads the keypair stored at `path`, generating and saving a new ed25519 keypair if the file
+/// does not exist yet.
+pub fn load_or_generate(path: &Path) -> Result<Keypair, KeypairError> {
+    let io_error = |source| KeypairError::Io {
+        path: path.to_owned(),
+        source,
+    };
+
+    match fs::read(path) {
+        Ok(bytes) => {
+            Keypair::from_protobuf_encoding(&bytes).map_err(|source| KeypairError::Decode {
+                path: path.to_owned(),
+                source,
+            })
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let keypair = Keypair::generate_ed25519();
+            let bytes = keypair
+                .to_protobuf_encoding()
+                .expect("ed25519 keypairs can always be encoded");
+            write_private(path, &bytes).map_err(io_error)?;
+            tracing::info!("Generated new keypair at {}", path.display());
+            Ok(keypair)
+        }
+        Err(err) => Err(io_error(err)),
+    }
+}
+
+/// Deterministically derives an ed25519 keypair from `seed`, so tests get stable peer ids.
+pub fn from_seed(seed: u8) -> Keypair {
+    let mut bytes = [0u8; 32];
+    bytes[0] = seed;
+    Keypair::ed25519_from_bytes(bytes).expect("32 bytes are a valid ed25519 secret key")
+}
+
+pub(crate) fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    io::Write::write_all(&mut options.open(path)?, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let unique = Keypair::generate_ed25519().public().to_peer_id();
+        std::env::temp_dir().join(format!("lp2p-{name}-{unique}.key"))
+    }
+
+    #[test]
+    fn reload_returns_the_same_identity() {
+        let path = temp_path("reload");
+        let generated = load_or_generate(&path).unwrap();
+        let reloaded = load_or_generate(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            generated.public().to_peer_id(),
+            reloaded.public().to_peer_id()
+        );
+    }
+
+    #[test]
+    fn seed_is_deterministic() {
+        assert_eq!(
+            from_seed(1).public().to_peer_id(),
+            from_seed(1).public().to_peer_id()
+        );
+        assert_ne!(
+            from_seed(1).public().to_peer_id(),
+            from_seed(2).public().to_peer_id()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("private");
+        load_or_generate(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn existing_file_is_not_clobbered() {
+        let path = temp_path("existing");
+        fs::write(&path, b"foo").unwrap();
+        let result = write_private(&path, b"bar");
+        let contents = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(contents, b"foo");
+    }
+
+    #[test]
+    fn malformed_key_file() {
+        let path = temp_path("malformed");
+        fs::write(&path, b"foo").unwrap();
+        let result = load_or_generate(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(KeypairError::Decode { .. })));
+    }
+}
@@ -1,4 +1,31 @@
-use libp2p::{core, Multiaddr, PeerId};
+pub mod address_policy;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod certificate;
+pub mod closest_peers;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod config;
+mod error;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod keypair;
+pub mod lookup;
+pub mod providers;
+pub mod publish;
+pub mod record;
+pub mod records;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod store;
+pub mod swarm;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tls;
+pub mod validation;
+
+use libp2p::{
+    core,
+    kad::{self, store::RecordStore, QueryId},
+    Multiaddr, PeerId,
+};
+
+pub use crate::error::Error;
 
 pub fn extract_peer_id(maddr: &Multiaddr) -> Option<PeerId> {
     match maddr.iter().last() {
@@ -6,3 +33,40 @@ pub fn extract_peer_id(maddr: &Multiaddr) -> Option<PeerId> {
         _ => None,
     }
 }
+
+/// Strips a trailing `/p2p` segment, for addresses listed under a peer id already.
+pub fn without_p2p(mut maddr: Multiaddr) -> Multiaddr {
+    if let Some(core::multiaddr::Protocol::P2p(_)) = maddr.iter().last() {
+        maddr.pop();
+    }
+    maddr
+}
+
+/// The addresses of `peer_id` in the local routing table, if it is in there.
+pub(crate) fn routing_table_addresses<S>(
+    kad: &mut kad::Behaviour<S>,
+    peer_id: PeerId,
+) -> Vec<Multiaddr>
+where
+    S: RecordStore + Send + 'static,
+{
+    let Some(bucket) = kad.kbucket(peer_id) else {
+        return vec![];
+    };
+    let addresses = bucket
+        .iter()
+        .find(|entry| *entry.node.key.preimage() == peer_id)
+        .map(|entry| entry.node.value.iter().cloned().collect())
+        .unwrap_or_default();
+    addresses
+}
+
+/// Finishes `query_id` early, if Kademlia is still running it.
+pub(crate) fn finish_query<S>(kad: &mut kad::Behaviour<S>, query_id: &QueryId)
+where
+    S: RecordStore + Send + 'static,
+{
+    if let Some(mut query) = kad.query_mut(query_id) {
+        query.finish();
+    }
+}
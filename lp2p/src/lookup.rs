@@ -0,0 +1,931 @@
+//! Bookkeeping for concurrent address lookups.
+//!
+//! Each lookup is a Kademlia `GetRecord` query, its progress events are routed by [`QueryId`] to
+//! a dedicated channel, so callers can have any number of lookups in flight on the same swarm.
+//!
+//! Kademlia may return several copies of a record, some of them stale. Depending on the
+//! [`LookupConfig`], a lookup resolves on the first valid copy or gathers a quorum of them and
+//! picks the one with the highest sequence number, optionally pushing it back to the peers that
+//! returned older copies.
+//!
+//! A peer that never published a record may still be known to other peers. If no record is
+//! found, the lookup falls back to a `GetClosestPeers` walk towards the peer and returns the
+//! addresses the peers along the walk have for it in their routing tables, including the ones the
+//! local transports cannot dial.
+
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
+    time::Duration,
+};
+
+use libp2p::{
+    futures::channel::oneshot,
+    kad::{
+        self, store::RecordStore, GetClosestPeersError, GetClosestPeersResult, GetRecordOk,
+        GetRecordResult, PutRecordResult, QueryId,
+    },
+    swarm::DialError,
+    Multiaddr, PeerId, TransportError,
+};
+use serde::Serialize;
+use web_time::Instant;
+
+use crate::{
+    finish_query,
+    record::{self, Address, AddressRecord},
+    routing_table_addresses, without_p2p, Error,
+};
+
+pub type LookupResult = Result<LookupOutcome, Error>;
+
+/// The addresses found for a peer, along with how they were found.
+#[derive(Debug, Clone)]
+pub struct LookupOutcome {
+    pub peer_id: PeerId,
+    pub addresses: Vec<FoundAddress>,
+    /// The peer which stored the record, `None` if the addresses come from routing tables.
+    pub publisher: Option<PeerId>,
+    /// Time left until the record expires, `None` if the addresses come from routing tables or
+    /// the record came from its publisher, which keeps its own copy without expiry.
+    pub expires_in: Option<Duration>,
+    /// Statistics of the record query and of the fallback walk, if any.
+    pub stats: kad::QueryStats,
+    /// How far the peer which returned the record is from the local node: `0` if the record
+    /// was found in the local store, `1` if that peer was in the local routing table when the
+    /// lookup started and `2` if the lookup discovered it. This is not a hop count, Kademlia
+    /// does not report which peer referred which, so every discovered peer counts as `2`
+    /// however long the referral chain was. `None` if the addresses come from routing tables.
+    pub source_distance: Option<u32>,
+}
+
+/// Where an address returned by a lookup comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressSource {
+    /// The address record signed by the peer.
+    Record,
+    /// The routing table of a peer met during the fallback walk, unsigned and possibly stale.
+    RoutingTable,
+}
+
+/// An address of the looked up peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FoundAddress {
+    pub addr: Multiaddr,
+    /// Whether the peer confirmed the address to be reachable, always `false` for addresses
+    /// taken from routing tables.
+    pub confirmed: bool,
+    pub source: AddressSource,
+}
+
+impl From<Address> for FoundAddress {
+    fn from(address: Address) -> Self {
+        Self {
+            addr: address.addr,
+            confirmed: address.confirmed,
+            source: AddressSource::Record,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LookupConfig {
+    /// Number of valid copies to gather before resolving, a lookup also resolves when the
+    /// query ends with at least one valid copy. A quorum of one resolves on the first copy.
+    pub quorum: NonZeroUsize,
+    /// Write the freshest copy back to the peers which returned stale ones, as well as to the
+    /// closest peers which had none.
+    pub repair: bool,
+}
+
+impl Default for LookupConfig {
+    fn default() -> Self {
+        Self {
+            quorum: NonZeroUsize::MIN,
+            repair: false,
+        }
+    }
+}
+
+/// A valid copy of a record and the peer which returned it.
+struct RecordCopy {
+    source: Option<PeerId>,
+    record: kad::Record,
+    address_record: AddressRecord,
+}
+
+struct Lookup {
+    peer_id: PeerId,
+    reply: oneshot::Sender<LookupResult>,
+    copies: Vec<RecordCopy>,
+    stats: kad::QueryStats,
+    /// The peers in the local routing table when the lookup started.
+    known_peers: HashSet<PeerId>,
+}
+
+/// A lookup which found no record, walking towards the peer instead.
+struct Walk {
+    peer_id: PeerId,
+    reply: oneshot::Sender<LookupResult>,
+    /// Statistics of the record query which came before.
+    stats: kad::QueryStats,
+    /// Addresses learned from other peers which the local transports cannot dial, the walk
+    /// only returns the peers it reached.
+    undialable: Vec<Multiaddr>,
+    /// Why the record query failed, the lookup fails with it if the walk finds no address.
+    error: Error,
+}
+
+/// In-flight lookups, keyed by the query driving them.
+#[derive(Default)]
+pub struct Lookups {
+    config: LookupConfig,
+    pending: HashMap<QueryId, Lookup>,
+    walks: HashMap<QueryId, Walk>,
+    /// Write-backs of the freshest copy still in flight.
+    repairs: HashSet<QueryId>,
+}
+
+impl Lookups {
+    pub fn new(config: LookupConfig) -> Self {
+        Self {
+            config,
+            pending: HashMap::new(),
+            walks: HashMap::new(),
+            repairs: HashSet::new(),
+        }
+    }
+
+    /// Starts looking up the address record of `peer_id`, the result is sent to the returned
+    /// channel once [`Lookups::on_get_record`] has seen enough of the query.
+    pub fn start<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        peer_id: &PeerId,
+    ) -> oneshot::Receiver<LookupResult>
+    where
+        S: RecordStore + Send + 'static,
+    {
+        let (reply, response) = oneshot::channel();
+        self.start_with(kad, peer_id, reply);
+        response
+    }
+
+    /// Like [`Lookups::start`], for callers which already hold the sending half of a channel.
+    pub fn start_with<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        peer_id: &PeerId,
+        reply: oneshot::Sender<LookupResult>,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        let known_peers = kad
+            .kbuckets()
+            .flat_map(|bucket| {
+                bucket
+                    .iter()
+                    .map(|entry| *entry.node.key.preimage())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let query_id = kad.get_record(record::record_key(peer_id));
+        tracing::debug!("Sent GetRecord request for {peer_id}: {query_id:?}");
+        self.pending.insert(
+            query_id,
+            Lookup {
+                peer_id: *peer_id,
+                reply,
+                copies: vec![],
+                stats: kad::QueryStats::empty(),
+                known_peers,
+            },
+        );
+    }
+
+    /// Returns `true` once every lookup has been resolved and every write-back has completed.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.walks.is_empty() && self.repairs.is_empty()
+    }
+
+    /// Handles a `GetRecord` progress event and the query statistics so far, resolving the
+    /// matching lookup once enough valid copies were found or the query is over.
+    ///
+    /// Events for queries that were not started through [`Lookups`] are ignored.
+    pub fn on_get_record<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        query_id: QueryId,
+        result: GetRecordResult,
+        stats: kad::QueryStats,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        let Some(lookup) = self.pending.get_mut(&query_id) else {
+            return;
+        };
+        lookup.stats = stats;
+
+        match result {
+            Ok(GetRecordOk::FoundRecord(found)) => {
+                match record::verify_record(&found.record) {
+                    Ok(address_record) => {
+                        tracing::info!(
+                            "GetRecord returned the following record: {}::{:?} (seq {})",
+                            address_record.peer_id(),
+                            address_record.addresses(),
+                            address_record.seq()
+                        );
+                        lookup.copies.push(RecordCopy {
+                            source: found.peer,
+                            record: found.record,
+                            address_record,
+                        });
+                    }
+                    // Other peers may still hold a valid copy, keep the query running
+                    Err(err) => tracing::warn!("Rejected GetRecord result: {err}"),
+                }
+
+                if lookup.copies.len() >= self.config.quorum.get() {
+                    self.resolve(kad, query_id, vec![]);
+                }
+            }
+            Ok(GetRecordOk::FinishedWithNoAdditionalRecord { cache_candidates }) => {
+                self.resolve(kad, query_id, cache_candidates.into_values().collect());
+            }
+            Err(err) => {
+                tracing::error!("GetRecord failed with error: {err}");
+                if !lookup.copies.is_empty() {
+                    self.resolve(kad, query_id, vec![]);
+                } else if let kad::GetRecordError::Timeout { .. } = err {
+                    self.fail(kad, query_id, err.into());
+                } else {
+                    self.walk(kad, query_id, err.into());
+                }
+            }
+        }
+    }
+
+    /// Handles a `GetClosestPeers` result of a fallback walk, resolving the matching lookup with
+    /// the addresses the peers along the walk know for the looked up peer.
+    ///
+    /// Events for queries that were not started through [`Lookups`] are ignored.
+    pub fn on_get_closest_peers<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        query_id: QueryId,
+        result: GetClosestPeersResult,
+        stats: kad::QueryStats,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        let Some(walk) = self.walks.remove(&query_id) else {
+            return;
+        };
+
+        let peers = match result {
+            Ok(ok) => ok.peers,
+            // The peer may have been met before the timeout
+            Err(GetClosestPeersError::Timeout { peers, .. }) => peers,
+        };
+        let mut seen = HashSet::new();
+        let addresses = peers
+            .into_iter()
+            .filter(|peer| peer.peer_id == walk.peer_id)
+            // The walk only lists the addresses it learned from other peers
+            .flat_map(|peer| {
+                if peer.addrs.is_empty() {
+                    routing_table_addresses(kad, peer.peer_id)
+                } else {
+                    peer.addrs
+                }
+            })
+            .chain(walk.undialable)
+            .map(without_p2p)
+            .filter(|addr| seen.insert(addr.clone()))
+            .map(|addr| FoundAddress {
+                addr,
+                confirmed: false,
+                source: AddressSource::RoutingTable,
+            })
+            .collect::<Vec<_>>();
+
+        let result = if addresses.is_empty() {
+            tracing::info!("No peer knows the addresses of {}", walk.peer_id);
+            Err(walk.error)
+        } else {
+            tracing::info!(
+                "Found addresses of {} in routing tables: {addresses:?}",
+                walk.peer_id
+            );
+            Ok(LookupOutcome {
+                peer_id: walk.peer_id,
+                addresses,
+                publisher: None,
+                expires_in: None,
+                stats: walk.stats.merge(stats),
+                source_distance: None,
+            })
+        };
+        let _ = walk.reply.send(result);
+    }
+
+    /// Handles the outcome of a write-back started when resolving a lookup.
+    ///
+    /// Events for other `PutRecord` queries are ignored.
+    pub fn on_put_record(&mut self, query_id: QueryId, result: PutRecordResult) {
+        if !self.repairs.remove(&query_id) {
+            return;
+        }
+
+        match result {
+            Ok(ok) => tracing::info!("Wrote back record {:?}", ok.key),
+            Err(err) => tracing::warn!("Failed to write back record: {err}"),
+        }
+    }
+
+    /// Keeps the addresses of a walked towards peer which the local transports cannot dial, the
+    /// walk drops them although other peers reported them. Addresses which were dialed and
+    /// failed, e.g. because the connection was refused, are left out.
+    pub fn on_dial_failure(&mut self, peer_id: Option<PeerId>, error: &DialError) {
+        let (Some(peer_id), DialError::Transport(errors)) = (peer_id, error) else {
+            return;
+        };
+
+        for walk in self.walks.values_mut() {
+            if walk.peer_id == peer_id {
+                walk.undialable
+                    .extend(errors.iter().filter_map(|(addr, err)| match err {
+                        TransportError::MultiaddrNotSupported(_) => Some(addr.clone()),
+                        TransportError::Other(_) => None,
+                    }));
+            }
+        }
+    }
+
+    /// Stops the queries whose callers are no longer waiting for a result.
+    pub fn cancel_abandoned<S>(&mut self, kad: &mut kad::Behaviour<S>)
+    where
+        S: RecordStore + Send + 'static,
+    {
+        self.pending.retain(|query_id, lookup| {
+            if lookup.reply.is_canceled() {
+                finish_query(kad, query_id);
+                return false;
+            }
+            true
+        });
+        self.walks.retain(|query_id, walk| {
+            if walk.reply.is_canceled() {
+                finish_query(kad, query_id);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Resolves the lookup with its freshest copy, writing that copy back to the holders of
+    /// stale copies and to `cache_candidates` if repairing is enabled.
+    fn resolve<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        query_id: QueryId,
+        cache_candidates: Vec<PeerId>,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        if self
+            .pending
+            .get(&query_id)
+            .is_some_and(|lookup| lookup.copies.is_empty())
+        {
+            // No record was found or every record found was rejected
+            self.walk(kad, query_id, Error::NoRecord);
+            return;
+        }
+        let Some(lookup) = self.pending.remove(&query_id) else {
+            return;
+        };
+        finish_query(kad, &query_id);
+
+        let freshest = freshest(&lookup.copies).expect("lookup has at least one copy");
+
+        if self.config.repair {
+            let targets = repair_targets(&lookup.copies, freshest, cache_candidates);
+            if !targets.is_empty() {
+                tracing::info!(
+                    "Writing back record for {} to {targets:?}",
+                    freshest.address_record.peer_id()
+                );
+                let query_id = kad.put_record_to(
+                    freshest.record.clone(),
+                    targets.into_iter(),
+                    kad::Quorum::One,
+                );
+                self.repairs.insert(query_id);
+            }
+        }
+
+        let source_distance = match freshest.source {
+            None => 0,
+            Some(source) if lookup.known_peers.contains(&source) => 1,
+            Some(_) => 2,
+        };
+        let addresses = freshest.address_record.addresses().iter().cloned();
+        let _ = lookup.reply.send(Ok(LookupOutcome {
+            peer_id: lookup.peer_id,
+            addresses: addresses.map(FoundAddress::from).collect(),
+            publisher: freshest.record.publisher,
+            expires_in: freshest
+                .record
+                .expires
+                .map(|expires| expires.saturating_duration_since(Instant::now())),
+            stats: lookup.stats,
+            source_distance: Some(source_distance),
+        }));
+    }
+
+    /// Replaces the record query of a lookup which found no record with a walk towards the peer,
+    /// the lookup fails with `error` if the walk finds no address either.
+    fn walk<S>(&mut self, kad: &mut kad::Behaviour<S>, query_id: QueryId, error: Error)
+    where
+        S: RecordStore + Send + 'static,
+    {
+        let Some(lookup) = self.pending.remove(&query_id) else {
+            return;
+        };
+        finish_query(kad, &query_id);
+
+        let query_id = kad.get_closest_peers(lookup.peer_id);
+        tracing::debug!(
+            "No record found for {}, sent GetClosestPeers request: {query_id:?}",
+            lookup.peer_id
+        );
+        self.walks.insert(
+            query_id,
+            Walk {
+                peer_id: lookup.peer_id,
+                reply: lookup.reply,
+                stats: lookup.stats,
+                undialable: vec![],
+                error,
+            },
+        );
+    }
+
+    fn fail<S>(&mut self, kad: &mut kad::Behaviour<S>, query_id: QueryId, err: Error)
+    where
+        S: RecordStore + Send + 'static,
+    {
+        if let Some(lookup) = self.pending.remove(&query_id) {
+            finish_query(kad, &query_id);
+            let _ = lookup.reply.send(Err(err));
+        }
+    }
+}
+
+/// Returns the copy with the highest sequence number, the first one received among equals.
+fn freshest(copies: &[RecordCopy]) -> Option<&RecordCopy> {
+    // `max_by_key` returns the last maximum
+    copies
+        .iter()
+        .rev()
+        .max_by_key(|copy| copy.address_record.seq())
+}
+
+/// Returns the peers which returned an older copy than `freshest`, followed by
+/// `cache_candidates`, the closest peers which returned none.
+fn repair_targets(
+    copies: &[RecordCopy],
+    freshest: &RecordCopy,
+    cache_candidates: Vec<PeerId>,
+) -> Vec<PeerId> {
+    copies
+        .iter()
+        .filter(|copy| copy.address_record.seq() < freshest.address_record.seq())
+        .filter_map(|copy| copy.source)
+        .chain(cache_candidates)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{identity::Keypair, kad::store::MemoryStore};
+
+    use super::*;
+
+    fn kad() -> kad::Behaviour<MemoryStore> {
+        let local_id = PeerId::random();
+        kad::Behaviour::new(local_id, MemoryStore::new(local_id))
+    }
+
+    fn found(record: kad::Record) -> GetRecordResult {
+        Ok(GetRecordOk::FoundRecord(kad::PeerRecord {
+            peer: None,
+            record,
+        }))
+    }
+
+    fn finished() -> GetRecordResult {
+        Ok(GetRecordOk::FinishedWithNoAdditionalRecord {
+            cache_candidates: Default::default(),
+        })
+    }
+
+    /// Returns the query driving the lookup of `peer_id`.
+    fn query_id(lookups: &Lookups, kad: &kad::Behaviour<MemoryStore>, peer_id: &PeerId) -> QueryId {
+        *lookups
+            .pending
+            .keys()
+            .find(|query_id| {
+                kad.query(query_id).is_some_and(|query| match query.info() {
+                    kad::QueryInfo::GetRecord { key, .. } => *key == record::record_key(peer_id),
+                    _ => false,
+                })
+            })
+            .expect("a lookup is pending for the peer")
+    }
+
+    /// Ends the only fallback walk in flight, having met `peers` along the way.
+    fn end_walk(
+        lookups: &mut Lookups,
+        kad: &mut kad::Behaviour<MemoryStore>,
+        peers: Vec<kad::PeerInfo>,
+    ) {
+        let query_id = *lookups.walks.keys().next().expect("a walk is in flight");
+        let ok = kad::GetClosestPeersOk { key: vec![], peers };
+        lookups.on_get_closest_peers(kad, query_id, Ok(ok), kad::QueryStats::empty());
+    }
+
+    #[test]
+    fn results_reach_the_matching_lookup() {
+        let mut kad = kad();
+        let mut lookups = Lookups::default();
+        let first = Keypair::generate_ed25519();
+        let second = Keypair::generate_ed25519();
+        let mut first_result = lookups.start(&mut kad, &first.public().to_peer_id());
+        let mut second_result = lookups.start(&mut kad, &second.public().to_peer_id());
+        let first_query = query_id(&lookups, &kad, &first.public().to_peer_id());
+        let second_query = query_id(&lookups, &kad, &second.public().to_peer_id());
+
+        let record = record::new_record(&second, 1, vec![address(64001)]).unwrap();
+        lookups.on_get_record(
+            &mut kad,
+            second_query,
+            found(record),
+            kad::QueryStats::empty(),
+        );
+        assert!(matches!(
+            second_result.try_recv(),
+            Ok(Some(Ok(outcome))) if outcome.addresses == [address(64001).into()]
+        ));
+        assert!(matches!(first_result.try_recv(), Ok(None)));
+        assert!(kad.query(&second_query).is_none());
+
+        lookups.on_get_record(&mut kad, first_query, finished(), kad::QueryStats::empty());
+        assert!(matches!(first_result.try_recv(), Ok(None)));
+        end_walk(&mut lookups, &mut kad, vec![]);
+        assert!(matches!(
+            first_result.try_recv(),
+            Ok(Some(Err(Error::NoRecord)))
+        ));
+        assert!(lookups.is_empty());
+    }
+
+    #[test]
+    fn invalid_records_keep_the_lookup_running() {
+        let mut kad = kad();
+        let mut lookups = Lookups::default();
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let mut result = lookups.start(&mut kad, &peer_id);
+        let query_id = query_id(&lookups, &kad, &peer_id);
+
+        let forged = record::new_record(&Keypair::generate_ed25519(), 1, vec![]).unwrap();
+        let forged = kad::Record::new(record::record_key(&peer_id), forged.value);
+        lookups.on_get_record(&mut kad, query_id, found(forged), kad::QueryStats::empty());
+        assert!(matches!(result.try_recv(), Ok(None)));
+
+        let record = record::new_record(&keypair, 1, vec![]).unwrap();
+        lookups.on_get_record(&mut kad, query_id, found(record), kad::QueryStats::empty());
+        assert!(matches!(result.try_recv(), Ok(Some(Ok(_)))));
+    }
+
+    #[test]
+    fn foreign_queries_are_ignored() {
+        let mut kad = kad();
+        let mut lookups = Lookups::default();
+        let peer_id = PeerId::random();
+        let mut result = lookups.start(&mut kad, &peer_id);
+        let foreign = kad.get_record(record::record_key(&peer_id));
+
+        lookups.on_get_record(&mut kad, foreign, finished(), kad::QueryStats::empty());
+        assert!(matches!(result.try_recv(), Ok(None)));
+        assert!(!lookups.is_empty());
+    }
+
+    #[test]
+    fn abandoned_lookups_are_finished() {
+        let mut kad = kad();
+        let mut lookups = Lookups::default();
+        let peer_id = PeerId::random();
+        drop(lookups.start(&mut kad, &peer_id));
+        let query_id = query_id(&lookups, &kad, &peer_id);
+
+        lookups.cancel_abandoned(&mut kad);
+        assert!(lookups.is_empty());
+        assert!(kad.query(&query_id).is_none());
+    }
+
+    fn address(port: u16) -> Address {
+        Address {
+            addr: format!("/ip4/192.0.2.1/tcp/{port}").parse().unwrap(),
+            confirmed: false,
+        }
+    }
+
+    /// A copy of the record of `keypair` with sequence number `seq`, listing port `seq`.
+    fn copy(keypair: &Keypair, seq: u64, source: PeerId) -> RecordCopy {
+        let record = record::new_record(keypair, seq, vec![address(seq as u16)]).unwrap();
+        RecordCopy {
+            source: Some(source),
+            address_record: record::verify_record(&record).unwrap(),
+            record,
+        }
+    }
+
+    fn quorum(quorum: usize, repair: bool) -> LookupConfig {
+        LookupConfig {
+            quorum: NonZeroUsize::new(quorum).unwrap(),
+            repair,
+        }
+    }
+
+    #[test]
+    fn quorum_reached() {
+        let mut kad = kad();
+        let mut lookups = Lookups::new(quorum(2, false));
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let mut result = lookups.start(&mut kad, &peer_id);
+        let query_id = query_id(&lookups, &kad, &peer_id);
+
+        let older = copy(&keypair, 1, PeerId::random());
+        lookups.on_get_record(
+            &mut kad,
+            query_id,
+            found(older.record),
+            kad::QueryStats::empty(),
+        );
+        assert!(matches!(result.try_recv(), Ok(None)));
+
+        let newer = copy(&keypair, 2, PeerId::random());
+        lookups.on_get_record(
+            &mut kad,
+            query_id,
+            found(newer.record),
+            kad::QueryStats::empty(),
+        );
+        assert!(
+            matches!(result.try_recv(), Ok(Some(Ok(outcome))) if outcome.addresses == [address(2).into()])
+        );
+        assert!(lookups.is_empty());
+    }
+
+    #[test]
+    fn quorum_not_reached() {
+        let mut kad = kad();
+        let mut lookups = Lookups::new(quorum(3, false));
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let mut result = lookups.start(&mut kad, &peer_id);
+        let query_id = query_id(&lookups, &kad, &peer_id);
+
+        let copy = copy(&keypair, 1, PeerId::random());
+        lookups.on_get_record(
+            &mut kad,
+            query_id,
+            found(copy.record),
+            kad::QueryStats::empty(),
+        );
+        assert!(matches!(result.try_recv(), Ok(None)));
+
+        // The query ending short of the quorum still resolves with the copies it found
+        lookups.on_get_record(&mut kad, query_id, finished(), kad::QueryStats::empty());
+        assert!(
+            matches!(result.try_recv(), Ok(Some(Ok(outcome))) if outcome.addresses == [address(1).into()])
+        );
+    }
+
+    #[test]
+    fn quorum_not_reached_without_copies() {
+        let mut kad = kad();
+        let mut lookups = Lookups::new(quorum(3, false));
+        let peer_id = PeerId::random();
+        let mut result = lookups.start(&mut kad, &peer_id);
+        let query_id = query_id(&lookups, &kad, &peer_id);
+
+        let err = kad::GetRecordError::QuorumFailed {
+            key: record::record_key(&peer_id),
+            records: vec![],
+            quorum: NonZeroUsize::new(3).unwrap(),
+        };
+        lookups.on_get_record(&mut kad, query_id, Err(err), kad::QueryStats::empty());
+        assert!(matches!(result.try_recv(), Ok(None)));
+        end_walk(&mut lookups, &mut kad, vec![]);
+        assert!(matches!(
+            result.try_recv(),
+            Ok(Some(Err(Error::QuorumFailed)))
+        ));
+    }
+
+    #[test]
+    fn first_copy_wins_ties() {
+        let keypair = Keypair::generate_ed25519();
+        let first = copy(&keypair, 1, PeerId::random());
+        let mut second = copy(&keypair, 1, PeerId::random());
+        second.record = record::new_record(&keypair, 1, vec![address(2)]).unwrap();
+        second.address_record = record::verify_record(&second.record).unwrap();
+        let older = copy(&keypair, 0, PeerId::random());
+
+        let copies = [older, first, second];
+        let freshest = freshest(&copies).unwrap();
+        assert_eq!(freshest.source, copies[1].source);
+    }
+
+    #[test]
+    fn repair_skips_holders_of_the_freshest_copy() {
+        let keypair = Keypair::generate_ed25519();
+        let stale = PeerId::random();
+        let up_to_date = PeerId::random();
+        let candidate = PeerId::random();
+        let copies = [
+            copy(&keypair, 1, stale),
+            copy(&keypair, 2, up_to_date),
+            copy(&keypair, 2, up_to_date),
+        ];
+
+        let freshest = freshest(&copies).unwrap();
+        assert_eq!(
+            repair_targets(&copies, freshest, vec![candidate]),
+            [stale, candidate]
+        );
+    }
+
+    #[test]
+    fn repair_writes_back_stale_copies() {
+        let mut kad = kad();
+        let mut lookups = Lookups::new(quorum(2, true));
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let mut result = lookups.start(&mut kad, &peer_id);
+        let query_id = query_id(&lookups, &kad, &peer_id);
+
+        lookups.on_get_record(
+            &mut kad,
+            query_id,
+            found(copy(&keypair, 2, PeerId::random()).record),
+            kad::QueryStats::empty(),
+        );
+        let stale = kad::PeerRecord {
+            peer: Some(PeerId::random()),
+            record: copy(&keypair, 1, PeerId::random()).record,
+        };
+        lookups.on_get_record(
+            &mut kad,
+            query_id,
+            Ok(GetRecordOk::FoundRecord(stale)),
+            kad::QueryStats::empty(),
+        );
+
+        assert!(
+            matches!(result.try_recv(), Ok(Some(Ok(outcome))) if outcome.addresses == [address(2).into()])
+        );
+        // The write-back is still in flight
+        assert!(!lookups.is_empty());
+    }
+
+    #[test]
+    fn source_distance_tells_where_the_record_came_from() {
+        let mut kad = kad();
+        let known = PeerId::random();
+        kad.add_address(&known, "/ip4/192.0.2.1/tcp/64001".parse().unwrap());
+
+        for (peer, distance) in [(None, 0), (Some(known), 1), (Some(PeerId::random()), 2)] {
+            let mut lookups = Lookups::default();
+            let keypair = Keypair::generate_ed25519();
+            let peer_id = keypair.public().to_peer_id();
+            let mut result = lookups.start(&mut kad, &peer_id);
+            let query_id = query_id(&lookups, &kad, &peer_id);
+
+            let record = kad::PeerRecord {
+                peer,
+                record: record::new_record(&keypair, 1, vec![]).unwrap(),
+            };
+            lookups.on_get_record(
+                &mut kad,
+                query_id,
+                Ok(GetRecordOk::FoundRecord(record)),
+                kad::QueryStats::empty(),
+            );
+            assert!(matches!(
+                result.try_recv(),
+                Ok(Some(Ok(outcome))) if outcome.source_distance == Some(distance)
+            ));
+        }
+    }
+
+    fn from_routing_table(addr: &str) -> FoundAddress {
+        FoundAddress {
+            addr: addr.parse().unwrap(),
+            confirmed: false,
+            source: AddressSource::RoutingTable,
+        }
+    }
+
+    #[test]
+    fn walk_returns_the_addresses_of_the_looked_up_peer() {
+        let mut kad = kad();
+        let mut lookups = Lookups::default();
+        let peer_id = PeerId::random();
+        let mut result = lookups.start(&mut kad, &peer_id);
+        let query_id = query_id(&lookups, &kad, &peer_id);
+
+        lookups.on_get_record(&mut kad, query_id, finished(), kad::QueryStats::empty());
+        let peers = vec![
+            kad::PeerInfo {
+                peer_id,
+                addrs: vec![format!("/ip4/192.0.2.1/tcp/64001/p2p/{peer_id}")
+                    .parse()
+                    .unwrap()],
+            },
+            kad::PeerInfo {
+                peer_id: PeerId::random(),
+                addrs: vec!["/ip4/192.0.2.2/tcp/64001".parse().unwrap()],
+            },
+        ];
+        end_walk(&mut lookups, &mut kad, peers);
+        assert!(matches!(
+            result.try_recv(),
+            Ok(Some(Ok(outcome)))
+                if outcome.addresses == [from_routing_table("/ip4/192.0.2.1/tcp/64001")]
+                    && outcome.source_distance.is_none()
+        ));
+        assert!(lookups.is_empty());
+    }
+
+    #[test]
+    fn walk_takes_addresses_from_the_local_routing_table() {
+        let mut kad = kad();
+        let mut lookups = Lookups::default();
+        let peer_id = PeerId::random();
+        kad.add_address(&peer_id, "/ip4/192.0.2.1/tcp/64001".parse().unwrap());
+        let mut result = lookups.start(&mut kad, &peer_id);
+        let query_id = query_id(&lookups, &kad, &peer_id);
+
+        lookups.on_get_record(&mut kad, query_id, finished(), kad::QueryStats::empty());
+        let peers = vec![kad::PeerInfo {
+            peer_id,
+            addrs: vec![],
+        }];
+        end_walk(&mut lookups, &mut kad, peers);
+        assert!(matches!(
+            result.try_recv(),
+            Ok(Some(Ok(outcome))) if outcome.addresses == [from_routing_table("/ip4/192.0.2.1/tcp/64001")]
+        ));
+    }
+
+    #[test]
+    fn walk_keeps_only_undialable_addresses_of_failed_dials() {
+        let mut kad = kad();
+        let mut lookups = Lookups::default();
+        let peer_id = PeerId::random();
+        let mut result = lookups.start(&mut kad, &peer_id);
+        let query_id = query_id(&lookups, &kad, &peer_id);
+        lookups.on_get_record(&mut kad, query_id, finished(), kad::QueryStats::empty());
+
+        let unsupported: Multiaddr = "/ip4/192.0.2.1/udp/64003/quic-v1".parse().unwrap();
+        let refused: Multiaddr = "/ip4/192.0.2.1/tcp/64001".parse().unwrap();
+        let error = DialError::Transport(vec![
+            (
+                unsupported.clone(),
+                TransportError::MultiaddrNotSupported(unsupported.clone()),
+            ),
+            (
+                refused.clone(),
+                TransportError::Other(std::io::ErrorKind::ConnectionRefused.into()),
+            ),
+        ]);
+        lookups.on_dial_failure(Some(peer_id), &error);
+        // Failed dials to other peers are none of the walk's business
+        lookups.on_dial_failure(Some(PeerId::random()), &error);
+
+        end_walk(&mut lookups, &mut kad, vec![]);
+        assert!(matches!(
+            result.try_recv(),
+            Ok(Some(Ok(outcome)))
+                if outcome.addresses == [from_routing_table("/ip4/192.0.2.1/udp/64003/quic-v1")]
+        ));
+    }
+}
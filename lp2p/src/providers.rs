@@ -0,0 +1,106 @@
+//! Bookkeeping for concurrent provider queries.
+//!
+//! Content keys (e.g. CIDs) are advertised by long-running servers with `start_providing` and
+//! resolved to the peers serving them with `get_providers`. Like [`crate::lookup::Lookups`],
+//! results are routed by [`QueryId`] so any number of queries can share a swarm.
+
+use std::collections::{HashMap, HashSet};
+
+use libp2p::{
+    futures::channel::oneshot,
+    kad::{self, store::RecordStore, GetProvidersOk, GetProvidersResult, QueryId},
+    PeerId,
+};
+
+use crate::{finish_query, Error};
+
+pub type FindProvidersResult = Result<Vec<PeerId>, Error>;
+
+/// Returns the DHT key of the content key `key`, taken as its UTF-8 bytes.
+pub fn provider_key(key: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&key)
+}
+
+struct Find {
+    reply: oneshot::Sender<FindProvidersResult>,
+    providers: HashSet<PeerId>,
+}
+
+/// In-flight provider queries, keyed by the query driving them.
+#[derive(Default)]
+pub struct ProviderQueries {
+    finds: HashMap<QueryId, Find>,
+}
+
+impl ProviderQueries {
+    /// Looks up the providers of `key`, all providers found are sent to `reply` once the query
+    /// is over.
+    pub fn find<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        key: kad::RecordKey,
+        reply: oneshot::Sender<FindProvidersResult>,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        let query_id = kad.get_providers(key);
+        tracing::debug!("Sent GetProviders request: {query_id:?}");
+        self.finds.insert(
+            query_id,
+            Find {
+                reply,
+                providers: HashSet::new(),
+            },
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.finds.is_empty()
+    }
+
+    /// Handles a `GetProviders` progress event, ignoring queries not started through
+    /// [`ProviderQueries`].
+    pub fn on_get_providers(&mut self, query_id: QueryId, result: GetProvidersResult) {
+        let Some(find) = self.finds.get_mut(&query_id) else {
+            return;
+        };
+
+        match result {
+            Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
+                tracing::info!("GetProviders returned the following providers: {providers:?}");
+                find.providers.extend(providers);
+            }
+            Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {
+                if let Some(find) = self.finds.remove(&query_id) {
+                    let _ = find.reply.send(Ok(find.providers.into_iter().collect()));
+                }
+            }
+            Err(err) => {
+                tracing::error!("GetProviders failed with error: {err}");
+                if let Some(find) = self.finds.remove(&query_id) {
+                    // Providers found before the timeout are still worth returning
+                    let result = if find.providers.is_empty() {
+                        Err(Error::Timeout)
+                    } else {
+                        Ok(find.providers.into_iter().collect())
+                    };
+                    let _ = find.reply.send(result);
+                }
+            }
+        }
+    }
+
+    /// Stops the provider lookups whose callers are no longer waiting for a result.
+    pub fn cancel_abandoned<S>(&mut self, kad: &mut kad::Behaviour<S>)
+    where
+        S: RecordStore + Send + 'static,
+    {
+        self.finds.retain(|query_id, find| {
+            if find.reply.is_canceled() {
+                finish_query(kad, query_id);
+                return false;
+            }
+            true
+        });
+    }
+}
@@ -0,0 +1,197 @@
+//! Publishing the local node's own address record.
+//!
+//! Peers publish a record signed with their own keypair whenever their reachable addresses
+//! change, and republish it periodically so it is refreshed before it expires from the DHT.
+//! Confirmed external addresses, which include observed addresses AutoNAT found reachable, are
+//! always published. Listen addresses are published as unconfirmed if the [`AddressPolicy`]
+//! allows them.
+
+use std::{collections::HashSet, time::Duration};
+
+use libp2p::{
+    identity::Keypair,
+    kad::{self, store::RecordStore},
+    Multiaddr,
+};
+
+use crate::{
+    address_policy::AddressPolicy,
+    record::{self, Address},
+    without_p2p,
+};
+
+/// How often the record is signed and published again, well within the default 48 hour TTL.
+pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Keeps the DHT up to date with the addresses of the local node.
+pub struct Publisher {
+    identity: Keypair,
+    policy: AddressPolicy,
+    published: Vec<Address>,
+    /// Sequence number of the last signed record.
+    seq: u64,
+}
+
+impl Publisher {
+    pub fn new(identity: Keypair, policy: AddressPolicy) -> Self {
+        Self {
+            identity,
+            policy,
+            published: vec![],
+            seq: 0,
+        }
+    }
+
+    /// Publishes a record for the confirmed `external_addrs` and the allowed `listen_addrs`,
+    /// unless they are the addresses that were last published.
+    pub fn update<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        listen_addrs: Vec<Multiaddr>,
+        external_addrs: Vec<Multiaddr>,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        // AutoNAT adds our own `/p2p` suffix to confirmed addresses, the record already names us
+        let confirmed = external_addrs.into_iter().map(|addr| Address {
+            addr: without_p2p(addr),
+            confirmed: true,
+        });
+        let unconfirmed = listen_addrs
+            .into_iter()
+            .filter(|addr| self.policy.allows(addr))
+            .map(|addr| Address {
+                addr: without_p2p(addr),
+                confirmed: false,
+            });
+
+        // An address that is both listened on and confirmed is only published once, as confirmed
+        let mut seen = HashSet::new();
+        let addresses = confirmed
+            .chain(unconfirmed)
+            .filter(|address| seen.insert(address.addr.clone()))
+            .collect::<Vec<_>>();
+
+        if addresses.iter().collect::<HashSet<_>>() == self.published.iter().collect() {
+            return;
+        }
+
+        self.published = addresses;
+        self.publish(kad);
+    }
+
+    /// Signs the last published addresses again, giving the record a new sequence number.
+    pub fn republish<S>(&mut self, kad: &mut kad::Behaviour<S>)
+    where
+        S: RecordStore + Send + 'static,
+    {
+        self.publish(kad);
+    }
+
+    fn publish<S>(&mut self, kad: &mut kad::Behaviour<S>)
+    where
+        S: RecordStore + Send + 'static,
+    {
+        // An empty record withdraws addresses published before, but there is nothing to
+        // withdraw until the first record went out
+        if self.published.is_empty() && self.seq == 0 {
+            tracing::debug!("No reachable addresses, skipping publication of own record");
+            return;
+        }
+
+        // Records signed within the same millisecond must still supersede the previous one
+        self.seq = record::current_seq().max(self.seq + 1);
+        let record = match record::new_record(&self.identity, self.seq, self.published.clone()) {
+            Ok(record) => record,
+            Err(err) => {
+                tracing::error!("Failed to sign own address record: {err}");
+                return;
+            }
+        };
+
+        tracing::info!("Publishing own addresses: {:?}", self.published);
+        if let Err(err) = kad.put_record(record, kad::Quorum::One) {
+            tracing::error!("Failed to store own address record: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{kad::store::MemoryStore, PeerId};
+
+    use super::*;
+
+    /// Returns the record the publisher stored locally before putting it to the DHT.
+    fn stored(kad: &mut kad::Behaviour<MemoryStore>, peer_id: &PeerId) -> record::AddressRecord {
+        let record = kad.store_mut().get(&record::record_key(peer_id)).unwrap();
+        record::verify_record(&record).unwrap()
+    }
+
+    #[test]
+    fn sequence_numbers_increase() {
+        let identity = Keypair::generate_ed25519();
+        let peer_id = identity.public().to_peer_id();
+        let mut kad = kad::Behaviour::new(peer_id, MemoryStore::new(peer_id));
+        let mut publisher = Publisher::new(identity, AddressPolicy::default());
+
+        publisher.update(
+            &mut kad,
+            vec![],
+            vec!["/ip4/192.0.2.1/tcp/64001".parse().unwrap()],
+        );
+        let first = stored(&mut kad, &peer_id).seq();
+        publisher.republish(&mut kad);
+        assert!(stored(&mut kad, &peer_id).seq() > first);
+    }
+
+    #[test]
+    fn withdraws_addresses_with_an_empty_record() {
+        let identity = Keypair::generate_ed25519();
+        let peer_id = identity.public().to_peer_id();
+        let mut kad = kad::Behaviour::new(peer_id, MemoryStore::new(peer_id));
+        let mut publisher = Publisher::new(identity, AddressPolicy::default());
+
+        publisher.update(&mut kad, vec![], vec![]);
+        assert!(kad.store_mut().get(&record::record_key(&peer_id)).is_none());
+
+        publisher.update(
+            &mut kad,
+            vec![],
+            vec!["/ip4/192.0.2.1/tcp/64001".parse().unwrap()],
+        );
+        assert_eq!(stored(&mut kad, &peer_id).addresses().len(), 1);
+        publisher.update(&mut kad, vec![], vec![]);
+        assert!(stored(&mut kad, &peer_id).addresses().is_empty());
+    }
+
+    #[test]
+    fn publishes_external_and_allowed_listen_addresses() {
+        let identity = Keypair::generate_ed25519();
+        let peer_id = identity.public().to_peer_id();
+        let mut kad = kad::Behaviour::new(peer_id, MemoryStore::new(peer_id));
+        let mut publisher = Publisher::new(identity, AddressPolicy::default());
+
+        let external: Multiaddr = "/ip4/192.168.1.1/tcp/64001".parse().unwrap();
+        let public: Multiaddr = "/ip4/8.8.8.8/tcp/64001".parse().unwrap();
+        let private: Multiaddr = "/ip4/10.0.0.1/tcp/64001".parse().unwrap();
+        publisher.update(
+            &mut kad,
+            vec![public.clone(), private, external.clone()],
+            vec![external.clone()],
+        );
+        assert_eq!(
+            stored(&mut kad, &peer_id).addresses(),
+            [
+                Address {
+                    addr: external,
+                    confirmed: true
+                },
+                Address {
+                    addr: public,
+                    confirmed: false
+                },
+            ]
+        );
+    }
+}
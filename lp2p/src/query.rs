@@ -1,155 +1,636 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::{self, Read, Write},
+    num::NonZeroUsize,
+    path::PathBuf,
+    process::ExitCode,
+};
 
 use clap::Parser;
 use libp2p::{
-    core,
-    futures::StreamExt,
+    futures::{channel::oneshot, StreamExt},
     identify,
-    identity::{self, Keypair},
-    kad::{self, GetRecordOk, QueryResult, RecordKey},
-    noise,
-    swarm::{self, NetworkBehaviour, SwarmEvent},
-    tcp, websocket, yamux, Multiaddr, PeerId, Swarm, Transport,
+    identity::Keypair,
+    kad::{self, QueryResult},
+    relay,
+    swarm::{NetworkBehaviour, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
+};
+use lp2p::{
+    address_policy::{classify, Transport},
+    closest_peers::ClosestPeersQueries,
+    keypair::IdentityArgs,
+    lookup::{FoundAddress, LookupConfig, Lookups},
+    providers::ProviderQueries,
+    records::RecordQueries,
+    swarm::{SwarmBuilder, SwarmConfig},
+    validation::{PeerRecordValidator, RecordValidator},
+    Error,
 };
-use lp2p::extract_peer_id;
-use tokio_util::sync::CancellationToken;
+use serde::Serialize;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
 #[derive(Debug, Clone, clap::Parser)]
 struct App {
     bootnode: Multiaddr,
-    query: PeerId,
+
+    /// How the keys given to the subcommands are encoded.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    key_format: KeyFormat,
+
+    /// How results are written to stdout, one document per key.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    output: OutputFormat,
+
+    #[command(flatten)]
+    identity: IdentityArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum Command {
+    /// Look up the address records of peers, all lookups run concurrently.
+    Get {
+        #[arg(num_args = 1.., required = true)]
+        peers: Vec<PeerId>,
+
+        /// Number of valid records to gather per lookup before picking the freshest one.
+        #[arg(long, default_value = "1")]
+        quorum: NonZeroUsize,
+
+        /// Write the freshest record back to the peers which returned a stale one or none at all.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Fetch the records stored under arbitrary keys, without verifying them.
+    GetRaw {
+        #[arg(num_args = 1.., required = true)]
+        keys: Vec<String>,
+    },
+    /// Store a record under an arbitrary key.
+    ///
+    /// Servers only keep signed address records keyed by the peer id of their signer and
+    /// arbitrary values under keys starting with `/raw/`. They acknowledge records they drop all
+    /// the same, so any other record is refused before it is sent.
+    Put {
+        key: String,
+
+        /// Read the value from this file instead of stdin.
+        #[arg(long)]
+        value_file: Option<PathBuf>,
+    },
+    /// Find the peers closest to keys, along with their known addresses.
+    ///
+    /// Pass a peer id with `--key-format base58` to discover the peers around it, even if it has
+    /// not published an address record.
+    ClosestPeers {
+        #[arg(num_args = 1.., required = true)]
+        keys: Vec<String>,
+
+        /// Number of peers to return per key, at most 20 [default: the replication factor].
+        #[arg(short)]
+        k: Option<NonZeroUsize>,
+    },
+    /// Find the peers providing keys, along with the addresses of their records.
+    Providers {
+        #[arg(num_args = 1.., required = true)]
+        keys: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+enum KeyFormat {
+    /// The UTF-8 bytes of the key.
+    #[default]
+    Utf8,
+    Hex,
+    Base58,
+}
+
+impl KeyFormat {
+    fn decode(self, key: &str) -> Result<kad::RecordKey, Error> {
+        let bytes = match self {
+            KeyFormat::Utf8 => key.as_bytes().to_vec(),
+            KeyFormat::Hex => hex::decode(key).map_err(|err| Error::InvalidKey(err.to_string()))?,
+            KeyFormat::Base58 => bs58::decode(key)
+                .into_vec()
+                .map_err(|err| Error::InvalidKey(err.to_string()))?,
+        };
+        Ok(kad::RecordKey::new(&bytes))
+    }
+}
+
+/// A key as given on the command line and the DHT key it decodes to.
+struct Key {
+    text: String,
+    record_key: kad::RecordKey,
+}
+
+impl Key {
+    fn decode(format: KeyFormat, text: String) -> Result<Self, Error> {
+        let record_key = format.decode(&text)?;
+        Ok(Self { text, record_key })
+    }
+
+    fn hex(&self) -> String {
+        hex::encode(&self.record_key)
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+enum OutputFormat {
+    /// A JSON document per line, bytes are hex encoded.
+    #[default]
+    Json,
+    /// The same documents encoded as CBOR, a hex string per line.
+    CborHex,
+    /// Record values as is, addresses and peer ids one per line.
+    Raw,
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    // Logs go to stderr, stdout is reserved for the results
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::DEBUG))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(io::stderr)
+                .with_filter(LevelFilter::DEBUG),
+        )
         .init();
 
     let app = App::parse();
 
-    let identity = identity::Keypair::generate_ed25519();
-    let local_peer_id = identity.public().to_peer_id();
-    tracing::info!("Local peer id {}", local_peer_id);
-    let mut swarm = create_swarm(&identity, vec![app.bootnode]);
+    match run(app).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            tracing::error!("{err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+/// Runs every query of the command, failing with the error of the first unsuccessful one.
+async fn run(app: App) -> Result<(), Error> {
+    let identity = app.identity.keypair()?;
+    let swarm = create_swarm(&identity, vec![app.bootnode])?;
 
-    tracing::info!("PeerId bytes: {:?}", &app.query.to_bytes());
+    let mut state = State {
+        swarm,
+        lookups: Lookups::default(),
+        records: RecordQueries::default(),
+        closest_peers: ClosestPeersQueries::default(),
+        providers: ProviderQueries::default(),
+    };
+    let output = app.output;
+    let decode_keys = |keys: Vec<String>| {
+        keys.into_iter()
+            .map(|key| Key::decode(app.key_format, key))
+            .collect::<Result<Vec<_>, Error>>()
+    };
 
-    swarm
-        .behaviour_mut()
-        .kad
-        .get_record(RecordKey::new(&app.query.to_bytes()));
+    match app.command {
+        Command::Get {
+            peers,
+            quorum,
+            repair,
+        } => {
+            state.lookups = Lookups::new(LookupConfig { quorum, repair });
+            let responses = peers
+                .iter()
+                .map(|peer_id| {
+                    let kad = &mut state.swarm.behaviour_mut().kad;
+                    (peer_id, state.lookups.start(kad, peer_id))
+                })
+                .collect::<Vec<_>>();
+            state.run_until_done().await;
+
+            collect_results(output, take_results(responses), |_, outcome| {
+                AddressesOutput {
+                    peer_id: outcome.peer_id.to_string(),
+                    addresses: prefer_quic(outcome.addresses),
+                    publisher: outcome.publisher.map(|peer_id| peer_id.to_string()),
+                    expires_in_secs: outcome.expires_in.map(|expires_in| expires_in.as_secs()),
+                    requests: outcome.stats.num_requests(),
+                    source_distance: outcome.source_distance,
+                    elapsed_ms: outcome
+                        .stats
+                        .duration()
+                        .map(|elapsed| elapsed.as_millis() as u64),
+                }
+            })
+        }
+        Command::GetRaw { keys } => {
+            let responses = decode_keys(keys)?
+                .into_iter()
+                .map(|key| {
+                    let (reply, response) = oneshot::channel();
+                    let kad = &mut state.swarm.behaviour_mut().kad;
+                    state.records.get(kad, key.record_key.clone(), reply);
+                    (key, response)
+                })
+                .collect::<Vec<_>>();
+            state.run_until_done().await;
+
+            collect_results(
+                output,
+                take_results(responses),
+                |key, record: kad::PeerRecord| RecordOutput {
+                    key: key.hex(),
+                    publisher: record.record.publisher.map(|peer_id| peer_id.to_string()),
+                    source: record.peer.map(|peer_id| peer_id.to_string()),
+                    value: record.record.value,
+                },
+            )
+        }
+        Command::Put { key, value_file } => {
+            let key = Key::decode(app.key_format, key)?;
+            let value = match value_file {
+                Some(path) => std::fs::read(path),
+                None => {
+                    let mut value = vec![];
+                    io::stdin().read_to_end(&mut value).map(|_| value)
+                }
+            }
+            .map_err(Error::Input)?;
 
-    let mut state = State { swarm };
+            let record = kad::Record::new(key.record_key.clone(), value);
+            PeerRecordValidator::default().validate(&record)?;
 
-    let cancellation_token = CancellationToken::new();
+            let (reply, response) = oneshot::channel();
+            let kad = &mut state.swarm.behaviour_mut().kad;
+            state.records.put(kad, record, reply);
+            state.run_until_done().await;
 
-    loop {
-        tokio::select! {
-            event = state.swarm.select_next_some() => state.on_swarm_event(event, cancellation_token.child_token()),
-            _ = cancellation_token.cancelled() => break,
+            collect_results(output, take_results(vec![(key, response)]), |key, ()| {
+                KeyOutput { key: key.hex() }
+            })
+        }
+        Command::ClosestPeers { keys, k } => {
+            let responses = decode_keys(keys)?
+                .into_iter()
+                .map(|key| {
+                    let (reply, response) = oneshot::channel();
+                    let kad = &mut state.swarm.behaviour_mut().kad;
+                    state
+                        .closest_peers
+                        .find(kad, key.record_key.clone(), k, reply);
+                    (key, response)
+                })
+                .collect::<Vec<_>>();
+            state.run_until_done().await;
+
+            collect_results(output, take_results(responses), |key, peers| PeersOutput {
+                key: key.hex(),
+                peers: peers
+                    .into_iter()
+                    .map(|peer| PeerOutput {
+                        peer_id: peer.peer_id.to_string(),
+                        addresses: peer.addrs,
+                    })
+                    .collect(),
+            })
+        }
+        Command::Providers { keys } => {
+            let responses = decode_keys(keys)?
+                .into_iter()
+                .map(|key| {
+                    let (reply, response) = oneshot::channel();
+                    let kad = &mut state.swarm.behaviour_mut().kad;
+                    state.providers.find(kad, key.record_key.clone(), reply);
+                    (key, response)
+                })
+                .collect::<Vec<_>>();
+            state.run_until_done().await;
+
+            let results = take_results(responses);
+            let providers = results
+                .iter()
+                .filter_map(|(_, result)| result.as_ref().ok())
+                .flatten()
+                .copied()
+                .collect();
+            let addresses = state.resolve_providers(providers).await;
+
+            collect_results(output, results, |key, providers| ProvidersOutput {
+                key: key.hex(),
+                providers: providers
+                    .into_iter()
+                    .map(|peer_id| ProviderOutput {
+                        peer_id: peer_id.to_string(),
+                        addresses: addresses[&peer_id].clone(),
+                    })
+                    .collect(),
+            })
         }
     }
 }
 
-fn create_swarm(identity: &Keypair, bootnodes: Vec<Multiaddr>) -> Swarm<Behaviour> {
-    let local_peer_id = identity.public().to_peer_id();
-    tracing::info!("Local peer id: {local_peer_id}");
+/// Takes the result of every query out of its channel.
+fn take_results<Q: Display, T>(
+    responses: Vec<(Q, oneshot::Receiver<Result<T, Error>>)>,
+) -> Vec<(Q, Result<T, Error>)> {
+    responses
+        .into_iter()
+        .map(|(query, mut response)| match response.try_recv() {
+            Ok(Some(result)) => (query, result),
+            // Every query has been resolved once `State::run_until_done` returns
+            Ok(None) | Err(_) => unreachable!("query for {query} was not resolved"),
+        })
+        .collect()
+}
 
-    let noise_config = noise::Config::new(&identity).unwrap(); // TODO: proper error handling
-    let muxer_config = yamux::Config::default();
+/// Writes the result of every query to stdout, returning the error of the first unsuccessful
+/// one.
+fn collect_results<Q: Display, T, O: Output>(
+    format: OutputFormat,
+    results: Vec<(Q, Result<T, Error>)>,
+    to_output: impl Fn(&Q, T) -> O,
+) -> Result<(), Error> {
+    let mut first_error = None;
+    for (query, result) in results {
+        match result {
+            Ok(value) => write_output(format, &to_output(&query, value)),
+            Err(err) => {
+                tracing::error!("Query for {query} failed: {err}");
+                first_error.get_or_insert(err);
+            }
+        }
+    }
 
-    let tcp_config = tcp::Config::new();
-    let tcp_transport = tcp::tokio::Transport::new(tcp_config.clone());
+    first_error.map_or(Ok(()), Err)
+}
 
-    let ws = websocket::WsConfig::new(tcp::tokio::Transport::new(tcp_config));
+/// A query result as written to stdout.
+trait Output: Serialize {
+    /// The result in [`OutputFormat::Raw`].
+    fn raw(&self) -> Vec<u8>;
+}
 
-    Swarm::new(
-        ws.or_transport(tcp_transport)
-            .upgrade(core::upgrade::Version::V1Lazy)
-            .authenticate(noise_config)
-            .multiplex(muxer_config)
-            .boxed(),
-        Behaviour::new(identity.to_owned(), bootnodes),
-        local_peer_id,
-        swarm::Config::with_tokio_executor().with_idle_connection_timeout(Duration::from_secs(10)),
-    )
+fn write_output(format: OutputFormat, output: &impl Output) {
+    let bytes = match format {
+        OutputFormat::Json => {
+            let mut json = serde_json::to_vec(output).expect("Outputs are valid JSON");
+            json.push(b'\n');
+            json
+        }
+        OutputFormat::CborHex => {
+            let cbor =
+                cbor4ii::serde::to_vec(vec![], output).expect("Encoding to a Vec never fails");
+            format!("{}\n", hex::encode(cbor)).into_bytes()
+        }
+        OutputFormat::Raw => output.raw(),
+    };
+
+    // A closed stdout, e.g. when piped to `head`, is not worth failing the queries over
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(&bytes).and_then(|()| stdout.flush());
+}
+
+fn lines<T: Display>(items: impl IntoIterator<Item = T>) -> Vec<u8> {
+    let mut bytes = vec![];
+    for item in items {
+        let _ = writeln!(bytes, "{item}");
+    }
+    bytes
+}
+
+/// Moves QUIC addresses to the front of the confirmed and of the unconfirmed addresses, keeping
+/// the order of the record otherwise. QUIC needs fewer round trips to connect and hole punches
+/// more reliably than TCP, but a confirmed address is still the better bet.
+fn prefer_quic(mut addresses: Vec<FoundAddress>) -> Vec<FoundAddress> {
+    addresses.sort_by_key(|address| {
+        (
+            !address.confirmed,
+            classify(&address.addr).transport != Transport::Quic,
+        )
+    });
+    addresses
+}
+
+#[derive(Serialize)]
+struct AddressesOutput {
+    peer_id: String,
+    addresses: Vec<FoundAddress>,
+    publisher: Option<String>,
+    expires_in_secs: Option<u64>,
+    /// Kademlia requests sent, including failed ones.
+    requests: u32,
+    /// See [`lp2p::lookup::LookupOutcome::source_distance`].
+    source_distance: Option<u32>,
+    elapsed_ms: Option<u64>,
+}
+
+impl Output for AddressesOutput {
+    fn raw(&self) -> Vec<u8> {
+        lines(self.addresses.iter().map(|address| &address.addr))
+    }
+}
+
+#[derive(Serialize)]
+struct RecordOutput {
+    key: String,
+    #[serde(serialize_with = "hex::serde::serialize")]
+    value: Vec<u8>,
+    publisher: Option<String>,
+    /// The peer which returned the record, `None` if it was found in the local store.
+    source: Option<String>,
+}
+
+impl Output for RecordOutput {
+    fn raw(&self) -> Vec<u8> {
+        self.value.clone()
+    }
+}
+
+#[derive(Serialize)]
+struct KeyOutput {
+    key: String,
+}
+
+impl Output for KeyOutput {
+    fn raw(&self) -> Vec<u8> {
+        vec![]
+    }
+}
+
+#[derive(Serialize)]
+struct PeerOutput {
+    peer_id: String,
+    addresses: Vec<Multiaddr>,
+}
+
+#[derive(Serialize)]
+struct PeersOutput {
+    key: String,
+    peers: Vec<PeerOutput>,
+}
+
+impl Output for PeersOutput {
+    fn raw(&self) -> Vec<u8> {
+        lines(self.peers.iter().map(|peer| &peer.peer_id))
+    }
+}
+
+#[derive(Serialize)]
+struct ProviderOutput {
+    peer_id: String,
+    /// The addresses the provider was looked up under, empty if the lookup failed.
+    addresses: Vec<FoundAddress>,
+}
+
+#[derive(Serialize)]
+struct ProvidersOutput {
+    key: String,
+    providers: Vec<ProviderOutput>,
+}
+
+impl Output for ProvidersOutput {
+    fn raw(&self) -> Vec<u8> {
+        lines(self.providers.iter().map(|provider| &provider.peer_id))
+    }
+}
+
+fn create_swarm(identity: &Keypair, bootnodes: Vec<Multiaddr>) -> Result<Swarm<Behaviour>, Error> {
+    let mut builder = SwarmBuilder::new(identity.to_owned(), SwarmConfig::default());
+    let behaviour = Behaviour::new(&mut builder, bootnodes)?;
+    builder.build(behaviour)
 }
 
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     identify: identify::Behaviour,
     kad: kad::Behaviour<kad::store::MemoryStore>,
+    /// Lets queries contact peers that are only reachable through a relay.
+    relay_client: relay::client::Behaviour,
 }
 
 impl Behaviour {
-    fn new(keypair: Keypair, bootnodes: Vec<Multiaddr>) -> Self {
-        let identify = identify::Behaviour::new(identify::Config::new(
-            "/polka-test/identify/1.0.0".to_string(),
-            keypair.public(),
-        ));
-        let local_peer_id = keypair.public().to_peer_id();
-        let mut kad =
-            kad::Behaviour::new(local_peer_id, kad::store::MemoryStore::new(local_peer_id));
-        kad.set_mode(Some(kad::Mode::Client));
-        for maddr in bootnodes {
-            let peer = extract_peer_id(&maddr).unwrap();
-            kad.add_address(&peer, maddr);
-        }
-        Self { identify, kad }
+    fn new(builder: &mut SwarmBuilder, bootnodes: Vec<Multiaddr>) -> Result<Self, Error> {
+        let identify = builder.identify();
+        let kad = builder.kad(
+            kad::store::MemoryStore::new(builder.local_peer_id()),
+            bootnodes,
+        )?;
+        let relay_client = builder.relay_client();
+        Ok(Self {
+            identify,
+            kad,
+            relay_client,
+        })
     }
 }
 
 struct State {
     swarm: Swarm<Behaviour>,
+    lookups: Lookups,
+    records: RecordQueries,
+    closest_peers: ClosestPeersQueries,
+    providers: ProviderQueries,
 }
 
 impl State {
-    fn on_swarm_event(
+    async fn run_until_done(&mut self) {
+        while !self.lookups.is_empty()
+            || !self.records.is_empty()
+            || !self.closest_peers.is_empty()
+            || !self.providers.is_empty()
+        {
+            let event = self.swarm.select_next_some().await;
+            self.on_swarm_event(event);
+        }
+    }
+
+    /// Looks up the addresses of `providers`, provider records only name the peer. Providers
+    /// which cannot be found are returned without addresses.
+    async fn resolve_providers(
         &mut self,
-        event: SwarmEvent<BehaviourEvent>,
-        cancellation_token: CancellationToken,
-    ) {
+        providers: HashSet<PeerId>,
+    ) -> HashMap<PeerId, Vec<FoundAddress>> {
+        let responses = providers
+            .into_iter()
+            .map(|peer_id| {
+                let kad = &mut self.swarm.behaviour_mut().kad;
+                (peer_id, self.lookups.start(kad, &peer_id))
+            })
+            .collect::<Vec<_>>();
+        self.run_until_done().await;
+
+        take_results(responses)
+            .into_iter()
+            .map(|(peer_id, result)| {
+                let addresses = result
+                    .map(|outcome| outcome.addresses)
+                    .unwrap_or_else(|err| {
+                        tracing::warn!(
+                            "Failed to resolve the addresses of provider {peer_id}: {err}"
+                        );
+                        vec![]
+                    });
+                (peer_id, addresses)
+            })
+            .collect()
+    }
+
+    fn on_swarm_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
         match event {
-            SwarmEvent::Behaviour(event) => self.on_behaviour_event(event, cancellation_token),
+            SwarmEvent::Behaviour(event) => self.on_behaviour_event(event),
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                tracing::debug!("Failed to dial {peer_id:?}: {error}");
+                self.lookups.on_dial_failure(peer_id, &error);
+            }
             _ => tracing::debug!("Received unhandled event: {event:?}"),
         }
     }
 
-    fn on_behaviour_event(&mut self, event: BehaviourEvent, cancellation_token: CancellationToken) {
+    fn on_behaviour_event(&mut self, event: BehaviourEvent) {
         match event {
             BehaviourEvent::Identify(event) => {
                 tracing::debug!("Received unhandled identify event: {event:?}")
             }
+            BehaviourEvent::RelayClient(event) => {
+                tracing::debug!("Received unhandled relay client event: {event:?}")
+            }
             BehaviourEvent::Kad(event) => match event {
-                kad::Event::OutboundQueryProgressed { result, .. } => match result {
+                kad::Event::OutboundQueryProgressed {
+                    id, result, stats, ..
+                } => match result {
                     QueryResult::GetRecord(get_record_ok) => {
-                        match get_record_ok {
-                            Ok(ok) => {
-                                if let GetRecordOk::FoundRecord(record) = ok {
-                                    let peer_id =
-                                        PeerId::from_bytes(&record.record.key.to_vec()).unwrap();
-                                    let maddrs: Vec<Multiaddr> =
-                                        cbor4ii::serde::from_slice(&record.record.value).unwrap();
-                                    tracing::info!("GetRecord returned the following record: {peer_id}::{maddrs:?}");
-                                }
-                            }
-                            Err(err) => {
-                                tracing::error!("GetRecord failed with error: {err}");
-                            }
-                        }
-                        cancellation_token.cancel();
+                        let kad = &mut self.swarm.behaviour_mut().kad;
+                        self.lookups
+                            .on_get_record(kad, id, get_record_ok.clone(), stats);
+                        self.records.on_get_record(kad, id, get_record_ok);
+                    }
+                    QueryResult::PutRecord(put_record_ok) => {
+                        self.lookups.on_put_record(id, put_record_ok.clone());
+                        self.records.on_put_record(id, put_record_ok);
+                    }
+                    QueryResult::GetProviders(get_providers_ok) => {
+                        self.providers.on_get_providers(id, get_providers_ok)
+                    }
+                    QueryResult::GetClosestPeers(get_closest_peers_ok) => {
+                        self.lookups.on_get_closest_peers(
+                            &mut self.swarm.behaviour_mut().kad,
+                            id,
+                            get_closest_peers_ok.clone(),
+                            stats,
+                        );
+                        self.closest_peers.on_get_closest_peers(
+                            &mut self.swarm.behaviour_mut().kad,
+                            id,
+                            get_closest_peers_ok,
+                        );
                     }
-                    QueryResult::GetClosestPeers(peers) => match peers {
-                        Ok(peers) => {
-                            tracing::info!("Received peers: {peers:?}");
-                        }
-                        Err(err) => {
-                            tracing::error!("Failed to get closest peers with error: {err}")
-                        }
-                    },
                     _ => tracing::debug!(
                         "Received unhandled outbound query progress event: {result:?}"
                     ),
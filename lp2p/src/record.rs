@@ -0,0 +1,270 @@
+//! Signed address records stored in the DHT.
+//!
+//! A record is keyed by the bytes of the [`PeerId`] it describes and its value is the protobuf
+//! encoding of a [`SignedEnvelope`] wrapping an [`AddressRecord`]. Each address is marked as
+//! confirmed if AutoNAT found it reachable from the outside. Plain libp2p [`PeerRecord`]s are
+//! accepted as well, with all of their addresses unconfirmed.
+//!
+//! Only the peer owning the keypair can produce a valid record, so readers must always go through
+//! [`verify_record`].
+
+use std::time::SystemTime;
+
+use libp2p::{
+    core::{
+        peer_record::FromEnvelopeError,
+        signed_envelope::{DecodingError, ReadPayloadError},
+        PeerRecord, SignedEnvelope,
+    },
+    identity::{Keypair, ParseError, SigningError},
+    kad::{self, RecordKey},
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+
+/// Domain separation string of the envelope signature.
+const DOMAIN_SEP: &str = "polka-test-address-record";
+/// Payload type of the envelope, distinguishing it from a libp2p [`PeerRecord`].
+const PAYLOAD_TYPE: &[u8] = b"/polka-test/address-record/1.0.0";
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecordError {
+    #[error("record key is not a valid peer id: {0}")]
+    InvalidKey(#[from] ParseError),
+    #[error("record value is not a signed envelope: {0}")]
+    InvalidEnvelope(#[from] DecodingError),
+    #[error("record value is not a valid signed address record: {0}")]
+    InvalidSignature(#[from] ReadPayloadError),
+    #[error("record value is not a valid address record: {0}")]
+    InvalidPayload(#[from] cbor4ii::serde::DecodeError<std::convert::Infallible>),
+    #[error("record value is not a valid signed peer record: {0}")]
+    InvalidPeerRecord(#[from] FromEnvelopeError),
+    #[error("record was signed by {0}, which does not match its key")]
+    MismatchedPeerId(PeerId),
+}
+
+/// An address of a peer, as published in its record.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Address {
+    pub addr: Multiaddr,
+    /// Whether the peer confirmed the address to be reachable, e.g. through AutoNAT.
+    pub confirmed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    seq: u64,
+    addresses: Vec<Address>,
+}
+
+/// The verified contents of a record.
+#[derive(Debug, Clone)]
+pub struct AddressRecord {
+    peer_id: PeerId,
+    seq: u64,
+    addresses: Vec<Address>,
+}
+
+impl AddressRecord {
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Unix timestamp of the signature in milliseconds, higher is fresher. Records signed within
+    /// the same millisecond get consecutive numbers, so it may run slightly ahead of the clock.
+    /// Plain libp2p [`PeerRecord`]s count in seconds instead.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The published addresses, confirmed ones first.
+    pub fn addresses(&self) -> &[Address] {
+        &self.addresses
+    }
+}
+
+/// Returns the DHT key under which the address record of `peer_id` is stored.
+pub fn record_key(peer_id: &PeerId) -> RecordKey {
+    RecordKey::new(&peer_id.to_bytes())
+}
+
+/// The sequence number of a record signed now, the current Unix timestamp in milliseconds.
+///
+/// Seconds are too coarse, a node often signs several records within the same second and a
+/// quick restart could reuse the last sequence number.
+pub fn current_seq() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("now() is never before UNIX_EPOCH")
+        .as_millis() as u64
+}
+
+/// Signs `addresses` with `keypair` and wraps them in a record keyed by the keypair's [`PeerId`].
+///
+/// `seq` should be higher than that of any record previously signed with `keypair`, see
+/// [`current_seq`].
+pub fn new_record(
+    keypair: &Keypair,
+    seq: u64,
+    mut addresses: Vec<Address>,
+) -> Result<kad::Record, SigningError> {
+    let peer_id = keypair.public().to_peer_id();
+    // Stable, so the order among confirmed and unconfirmed addresses is kept
+    addresses.sort_by_key(|address| !address.confirmed);
+
+    let payload = cbor4ii::serde::to_vec(vec![], &Payload { seq, addresses })
+        .expect("Encoding to a Vec never fails");
+    let envelope = SignedEnvelope::new(
+        keypair,
+        DOMAIN_SEP.to_owned(),
+        PAYLOAD_TYPE.to_vec(),
+        payload,
+    )?;
+    Ok(kad::Record::new(
+        record_key(&peer_id),
+        envelope.into_protobuf_encoding(),
+    ))
+}
+
+/// Whether `record` is older than the `stored` copy, which happens when the copies of several
+/// records published in quick succession arrive out of order.
+pub fn is_stale(record: &kad::Record, stored: &kad::Record) -> bool {
+    match (verify_record(record), verify_record(stored)) {
+        (Ok(record), Ok(stored)) => record.seq() < stored.seq(),
+        _ => false,
+    }
+}
+
+/// Decodes a record and checks that it was signed by the peer named in its key.
+pub fn verify_record(record: &kad::Record) -> Result<AddressRecord, RecordError> {
+    let key = PeerId::from_bytes(record.key.as_ref())?;
+    let envelope = SignedEnvelope::from_protobuf_encoding(&record.value)?;
+
+    let signed_payload = envelope.payload_and_signing_key(DOMAIN_SEP.to_owned(), PAYLOAD_TYPE);
+    let address_record = match signed_payload {
+        Ok((payload, signing_key)) => {
+            let payload: Payload = cbor4ii::serde::from_slice(payload)?;
+            AddressRecord {
+                peer_id: signing_key.to_peer_id(),
+                seq: payload.seq,
+                addresses: payload.addresses,
+            }
+        }
+        Err(ReadPayloadError::UnexpectedPayloadType { .. }) => {
+            let peer_record = PeerRecord::from_signed_envelope(envelope)?;
+            AddressRecord {
+                peer_id: peer_record.peer_id(),
+                seq: peer_record.seq(),
+                addresses: peer_record
+                    .addresses()
+                    .iter()
+                    .map(|addr| Address {
+                        addr: addr.clone(),
+                        confirmed: false,
+                    })
+                    .collect(),
+            }
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if address_record.peer_id != key {
+        return Err(RecordError::MismatchedPeerId(address_record.peer_id));
+    }
+
+    Ok(address_record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(addr: &str, confirmed: bool) -> Address {
+        Address {
+            addr: addr.parse().unwrap(),
+            confirmed,
+        }
+    }
+
+    #[test]
+    fn valid_record() {
+        let keypair = Keypair::generate_ed25519();
+        let addresses = vec![
+            address("/ip4/192.0.2.1/tcp/64001", false),
+            address("/ip4/192.0.2.1/tcp/64002/ws", true),
+        ];
+        let record = new_record(&keypair, 42, addresses).unwrap();
+
+        let verified = verify_record(&record).unwrap();
+        assert_eq!(verified.peer_id(), keypair.public().to_peer_id());
+        assert_eq!(verified.seq(), 42);
+        assert_eq!(
+            verified.addresses(),
+            [
+                address("/ip4/192.0.2.1/tcp/64002/ws", true),
+                address("/ip4/192.0.2.1/tcp/64001", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn record_under_another_peers_key() {
+        let victim = Keypair::generate_ed25519().public().to_peer_id();
+        let attacker = Keypair::generate_ed25519();
+        let record = new_record(&attacker, 1, vec![]).unwrap();
+        let forged = kad::Record::new(record_key(&victim), record.value);
+
+        assert!(matches!(
+            verify_record(&forged),
+            Err(RecordError::MismatchedPeerId(peer_id))
+                if peer_id == attacker.public().to_peer_id()
+        ));
+    }
+
+    #[test]
+    fn tampered_payload() {
+        let keypair = Keypair::generate_ed25519();
+        let mut record = new_record(
+            &keypair,
+            1,
+            vec![address("/ip4/192.0.2.1/tcp/64001", false)],
+        )
+        .unwrap();
+
+        // Flip the last octet of the address inside the signed payload
+        let addr = address("/ip4/192.0.2.1/tcp/64001", false).addr.to_vec();
+        let position = record
+            .value
+            .windows(addr.len())
+            .position(|window| window == addr)
+            .expect("the payload contains the address");
+        record.value[position + 4] ^= 1;
+
+        assert!(matches!(
+            verify_record(&record),
+            Err(RecordError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn plain_peer_record() {
+        let keypair = Keypair::generate_ed25519();
+        let addr: Multiaddr = "/ip4/192.0.2.1/tcp/64001".parse().unwrap();
+        let peer_record = PeerRecord::new(&keypair, vec![addr.clone()]).unwrap();
+        let record = kad::Record::new(
+            record_key(&keypair.public().to_peer_id()),
+            peer_record.to_signed_envelope().into_protobuf_encoding(),
+        );
+
+        let verified = verify_record(&record).unwrap();
+        assert_eq!(verified.peer_id(), keypair.public().to_peer_id());
+        assert_eq!(verified.seq(), peer_record.seq());
+        assert_eq!(
+            verified.addresses(),
+            [Address {
+                addr,
+                confirmed: false
+            }]
+        );
+    }
+}
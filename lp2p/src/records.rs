@@ -0,0 +1,129 @@
+//! Bookkeeping for raw record queries.
+//!
+//! Unlike [`crate::lookup::Lookups`], records are neither verified nor tied to a peer id, which
+//! makes these queries useful to inspect and seed the DHT with arbitrary keys. Peers running
+//! [`crate::validation::PeerRecordValidator`] only keep signed address records and values under
+//! keys starting with [`crate::validation::RAW_KEY_PREFIX`].
+
+use std::collections::HashMap;
+
+use libp2p::{
+    futures::channel::oneshot,
+    kad::{self, store::RecordStore, GetRecordOk, GetRecordResult, PutRecordResult, QueryId},
+};
+
+use crate::{finish_query, Error};
+
+pub type GetRawResult = Result<kad::PeerRecord, Error>;
+pub type PutResult = Result<(), Error>;
+
+/// In-flight raw record queries, keyed by the query driving them.
+#[derive(Default)]
+pub struct RecordQueries {
+    gets: HashMap<QueryId, oneshot::Sender<GetRawResult>>,
+    puts: HashMap<QueryId, oneshot::Sender<PutResult>>,
+}
+
+impl RecordQueries {
+    /// Looks up the record stored under `key`, the first copy found is sent to `reply` as is.
+    pub fn get<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        key: kad::RecordKey,
+        reply: oneshot::Sender<GetRawResult>,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        let query_id = kad.get_record(key);
+        tracing::debug!("Sent GetRecord request: {query_id:?}");
+        self.gets.insert(query_id, reply);
+    }
+
+    /// Stores `record` locally and on the closest peers, the result is sent to `reply` once at
+    /// least one of them accepted it.
+    pub fn put<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        record: kad::Record,
+        reply: oneshot::Sender<PutResult>,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        match kad.put_record(record, kad::Quorum::One) {
+            Ok(query_id) => {
+                tracing::debug!("Sent PutRecord request: {query_id:?}");
+                self.puts.insert(query_id, reply);
+            }
+            Err(err) => {
+                let _ = reply.send(Err(err.into()));
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gets.is_empty() && self.puts.is_empty()
+    }
+
+    /// Handles a `GetRecord` progress event, ignoring queries not started through
+    /// [`RecordQueries`].
+    pub fn on_get_record<S>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        query_id: QueryId,
+        result: GetRecordResult,
+    ) where
+        S: RecordStore + Send + 'static,
+    {
+        let Some(reply) = self.gets.remove(&query_id) else {
+            return;
+        };
+
+        let result = match result {
+            Ok(GetRecordOk::FoundRecord(record)) => {
+                finish_query(kad, &query_id);
+                Ok(record)
+            }
+            Ok(GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => Err(Error::NoRecord),
+            Err(err) => {
+                tracing::error!("GetRecord failed with error: {err}");
+                Err(err.into())
+            }
+        };
+        let _ = reply.send(result);
+    }
+
+    /// Handles a `PutRecord` result, ignoring queries not started through [`RecordQueries`].
+    pub fn on_put_record(&mut self, query_id: QueryId, result: PutRecordResult) {
+        let Some(reply) = self.puts.remove(&query_id) else {
+            return;
+        };
+
+        let result = match result {
+            Ok(ok) => {
+                tracing::info!("Stored record {:?}", ok.key);
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!("PutRecord failed with error: {err}");
+                Err(err.into())
+            }
+        };
+        let _ = reply.send(result);
+    }
+
+    /// Stops the `GetRecord` queries whose callers are no longer waiting for a result.
+    pub fn cancel_abandoned<S>(&mut self, kad: &mut kad::Behaviour<S>)
+    where
+        S: RecordStore + Send + 'static,
+    {
+        self.gets.retain(|query_id, reply| {
+            if reply.is_canceled() {
+                finish_query(kad, query_id);
+                return false;
+            }
+            true
+        });
+        // The record is already stored locally, so an abandoned `put` keeps replicating it
+        self.puts.retain(|_, reply| !reply.is_canceled());
+    }
+}
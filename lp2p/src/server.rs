@@ -1,17 +1,38 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
 
 use clap::Parser;
 use libp2p::{
-    autonat, core,
+    autonat, dcutr,
     futures::StreamExt,
     identify,
-    identity::{self, Keypair},
-    kad::{self, InboundRequest, QueryResult, Record},
-    noise, ping,
-    swarm::{self, NetworkBehaviour, SwarmEvent},
-    tcp, websocket, yamux, Multiaddr, Swarm, Transport,
+    identity::Keypair,
+    kad::{self, store::RecordStore, GetRecordOk, InboundRequest, QueryResult},
+    ping, relay,
+    swarm::{dial_opts::DialOpts, NetworkBehaviour, SwarmEvent},
+    websocket::tls as ws_tls,
+    Multiaddr, PeerId, Swarm,
+};
+use libp2p_webrtc::tokio::Certificate;
+use lp2p::{
+    address_policy::AddressPolicy,
+    certificate,
+    config::{Config, RecordsConfig},
+    keypair::IdentityArgs,
+    providers::provider_key,
+    publish::Publisher,
+    record,
+    store::{FileStore, DEFAULT_FLUSH_INTERVAL},
+    swarm::{SwarmBuilder, SwarmConfig},
+    tls::{self, TlsArgs, TlsReloader},
+    validation::{PeerRecordValidator, RecordValidator},
+    Error,
 };
-use lp2p::extract_peer_id;
+use tokio::signal::unix::{signal, SignalKind};
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
@@ -22,10 +43,47 @@ struct App {
 
     #[arg(short='b', value_delimiter=',', num_args=1..)]
     bootnodes: Vec<Multiaddr>,
+
+    /// Addresses this server is known to be reachable at, e.g. behind port forwarding. They are
+    /// published as confirmed and handed out in relay reservations, which are refused while the
+    /// server has no external address.
+    #[arg(long, value_delimiter = ',')]
+    external_addrs: Vec<Multiaddr>,
+
+    /// PEM certificate file for webrtc-direct listen addresses, a new certificate is written to it
+    /// if it does not exist. Without it the certificate, and with it the `/certhash` of the
+    /// addresses, changes on every start.
+    #[arg(long)]
+    webrtc_certificate: Option<PathBuf>,
+
+    /// File to persist DHT records to, records are kept in memory only if omitted.
+    #[arg(long)]
+    store_path: Option<PathBuf>,
+
+    /// Also publish loopback, link-local and private listen addresses, for local testing.
+    #[arg(long)]
+    allow_private: bool,
+
+    /// Content keys to advertise this server as a provider of, Kademlia republishes them.
+    #[arg(long, value_delimiter = ',')]
+    provide: Vec<String>,
+
+    /// TOML config file, command line flags take precedence over its values.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    records: RecordsConfig,
+
+    #[command(flatten)]
+    tls: TlsArgs,
+
+    #[command(flatten)]
+    identity: IdentityArgs,
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer())
         .with(
@@ -38,153 +96,410 @@ async fn main() {
 
     let app = App::parse();
 
-    let mut swarm = create_swarm(app.bootnodes);
+    match run(app).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            tracing::error!("{err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+async fn run(app: App) -> Result<(), Error> {
+    let config = match &app.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let records = app.records.or(config.records);
+
+    let identity = app.identity.keypair()?;
+    let store = FileStore::open(
+        identity.public().to_peer_id(),
+        Default::default(),
+        app.store_path,
+    )?;
+    let policy = AddressPolicy {
+        allow_private: app.allow_private,
+    };
+    let webrtc_certificate = match &app.webrtc_certificate {
+        Some(path) => certificate::load_or_generate(path)?,
+        None => certificate::generate(),
+    };
+    let (mut swarm, tls_reloader) = create_swarm(
+        &identity,
+        app.bootnodes,
+        store,
+        &records,
+        policy,
+        webrtc_certificate,
+        app.tls.config()?,
+    )?;
     for addr in app.listen_addrs {
-        swarm.listen_on(addr).unwrap();
+        swarm.listen_on(addr)?;
+    }
+    for addr in app.external_addrs {
+        swarm.add_external_address(addr);
     }
+    for key in &app.provide {
+        tracing::info!("Providing {key}");
+        swarm
+            .behaviour_mut()
+            .kad
+            .start_providing(provider_key(key))?;
+    }
+
+    let mut state = State {
+        swarm,
+        publisher: Publisher::new(identity, policy),
+        validator: Box::new(PeerRecordValidator::default()),
+        unreachable_since: HashMap::new(),
+        drop_unreachable_after: records.drop_unreachable_after(),
+    };
+
+    let mut republish = tokio::time::interval(records.republish_interval());
+    // The first tick completes immediately, the record is published once we are listening
+    republish.tick().await;
+    // Peers are probed a few times before their record is dropped
+    let mut probe =
+        tokio::time::interval((records.drop_unreachable_after() / 4).max(Duration::from_secs(1)));
+    let mut flush = tokio::time::interval(DEFAULT_FLUSH_INTERVAL);
+    let mut hangup = signal(SignalKind::hangup()).map_err(Error::Signal)?;
 
     loop {
         tokio::select! {
-            event = swarm.select_next_some() => on_swarm_event(&mut swarm, event)
+            event = state.swarm.select_next_some() => state.on_swarm_event(event),
+            _ = republish.tick() => state.publisher.republish(&mut state.swarm.behaviour_mut().kad),
+            _ = flush.tick() => {
+                if let Err(err) = state.swarm.behaviour_mut().kad.store_mut().flush() {
+                    tracing::error!("Failed to persist record store: {err}");
+                }
+            }
+            _ = probe.tick() => state.probe_record_owners(),
+            _ = hangup.recv() => reload_tls(&app.tls, tls_reloader.as_ref()),
         }
     }
 }
 
+/// Reads `--tls-cert` and `--tls-key` again, the previous certificate is kept if they are invalid.
+fn reload_tls(args: &TlsArgs, reloader: Option<&TlsReloader>) {
+    let (Some((cert, key)), Some(reloader)) = (args.files(), reloader) else {
+        tracing::debug!("Ignoring SIGHUP, no TLS certificate files were given");
+        return;
+    };
+    match tls::load(cert, key) {
+        Ok(config) => reloader.reload(config),
+        Err(err) => tracing::error!("Keeping the current TLS certificate: {err}"),
+    }
+}
+
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     ping: ping::Behaviour,
     identify: identify::Behaviour,
-    kad: kad::Behaviour<kad::store::MemoryStore>,
+    kad: kad::Behaviour<FileStore>,
     autonat: autonat::Behaviour,
+    relay: relay::Behaviour,
+    /// Lets record owners which are only reachable through another relay be probed.
+    relay_client: relay::client::Behaviour,
+    /// Upgrades relayed connections to direct ones by hole punching.
+    dcutr: dcutr::Behaviour,
 }
 
 impl Behaviour {
-    fn new(keypair: Keypair, bootnodes: Vec<Multiaddr>) -> Self {
+    fn new(
+        builder: &mut SwarmBuilder,
+        bootnodes: Vec<Multiaddr>,
+        store: FileStore,
+        policy: AddressPolicy,
+    ) -> Result<Self, Error> {
         let ping = ping::Behaviour::new(ping::Config::default());
+        let identify = builder.identify();
+        let kad = builder.kad(store, bootnodes.clone())?;
+        let autonat = builder.autonat(policy.autonat_config(), &bootnodes);
+        let relay = relay::Behaviour::new(builder.local_peer_id(), relay::Config::default());
+        let relay_client = builder.relay_client();
 
-        let identify = identify::Behaviour::new(identify::Config::new(
-            "/polka-test/identify/1.0.0".to_string(),
-            keypair.public(),
-        ));
-
-        let local_peer_id = keypair.public().to_peer_id();
-        let mut kad =
-            kad::Behaviour::new(local_peer_id, kad::store::MemoryStore::new(local_peer_id));
-        kad.set_mode(Some(kad::Mode::Server));
-
-        for node in bootnodes {
-            tracing::info!("Adding address to Kademlia: {node}");
-            kad.add_address(&extract_peer_id(&node).unwrap(), node);
-        }
-
-        let autonat = autonat::Behaviour::new(local_peer_id, autonat::Config::default());
-
-        Self {
+        Ok(Self {
             ping,
             identify,
             kad,
             autonat,
-        }
+            relay,
+            relay_client,
+            dcutr: dcutr::Behaviour::new(builder.local_peer_id()),
+        })
     }
 }
 
-fn create_swarm(bootnodes: Vec<Multiaddr>) -> Swarm<Behaviour> {
-    let identity = identity::Keypair::generate_ed25519();
-    let local_peer_id = identity.public().to_peer_id();
-    tracing::info!("Local peer id: {local_peer_id}");
-
-    let noise_config = noise::Config::new(&identity).unwrap(); // TODO: proper error handling
-    let muxer_config = yamux::Config::default();
-
-    let tcp_config = tcp::Config::new();
-    let tcp_transport = tcp::tokio::Transport::new(tcp_config.clone());
-
-    let ws = websocket::WsConfig::new(tcp::tokio::Transport::new(tcp_config));
-    let tcp_ws_transport = tcp_transport
-        .or_transport(ws)
-        .upgrade(core::upgrade::Version::V1Lazy)
-        .authenticate(noise_config)
-        .multiplex(muxer_config)
-        .boxed();
-
-    let local_peer_id = identity.public().to_peer_id();
-
-    Swarm::new(
-        tcp_ws_transport,
-        Behaviour::new(identity, bootnodes),
-        local_peer_id,
-        swarm::Config::with_tokio_executor(),
-    )
+fn create_swarm(
+    identity: &Keypair,
+    bootnodes: Vec<Multiaddr>,
+    store: FileStore,
+    records: &RecordsConfig,
+    policy: AddressPolicy,
+    webrtc_certificate: Certificate,
+    tls: Option<ws_tls::Config>,
+) -> Result<(Swarm<Behaviour>, Option<TlsReloader>), Error> {
+    let mut kad_config = kad::Config::new(kad::PROTOCOL_NAME);
+    // Inbound records go through `State::on_inbound_request` before being stored
+    kad_config.set_record_filtering(kad::StoreInserts::FilterBoth);
+    records.apply(&mut kad_config);
+
+    let mut builder = SwarmBuilder::new(
+        identity.to_owned(),
+        SwarmConfig {
+            kad_mode: kad::Mode::Server,
+            kad: kad_config,
+            webrtc_certificate: Some(webrtc_certificate),
+            ..Default::default()
+        },
+    );
+    let tls_reloader = tls.map(|config| builder.websocket_tls(config));
+    let behaviour = Behaviour::new(&mut builder, bootnodes, store, policy)?;
+    Ok((builder.build(behaviour)?, tls_reloader))
 }
 
-fn on_swarm_event(swarm: &mut Swarm<Behaviour>, event: SwarmEvent<BehaviourEvent>) {
-    match event {
-        SwarmEvent::NewListenAddr { address, .. } => {
-            tracing::debug!("New listen address: {address}");
-        }
-        SwarmEvent::ExternalAddrConfirmed { address } => {
-            tracing::debug!("Local external address confirmed: {address}")
-        }
-        SwarmEvent::NewExternalAddrOfPeer { peer_id, address } => {
-            tracing::debug!("External address confirmed: {address} for {peer_id}")
+struct State {
+    swarm: Swarm<Behaviour>,
+    publisher: Publisher,
+    validator: Box<dyn RecordValidator>,
+    /// Record owners which could not be dialed, with the time of the first failure.
+    unreachable_since: HashMap<PeerId, Instant>,
+    drop_unreachable_after: Duration,
+}
+
+impl State {
+    fn on_swarm_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                tracing::debug!("New listen address: {address}");
+                self.publish_own_record();
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                tracing::debug!("Expired listen address: {address}");
+                self.publish_own_record();
+            }
+            SwarmEvent::NewExternalAddrCandidate { address } => {
+                tracing::debug!("Local external address candidate: {address}")
+            }
+            SwarmEvent::ExternalAddrConfirmed { address } => {
+                tracing::debug!("Local external address confirmed: {address}");
+                self.publish_own_record();
+            }
+            SwarmEvent::ExternalAddrExpired { address } => {
+                tracing::debug!("Local external address expired: {address}");
+                self.publish_own_record();
+            }
+            SwarmEvent::NewExternalAddrOfPeer { peer_id, address } => {
+                tracing::debug!("External address confirmed: {address} for {peer_id}")
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                self.unreachable_since.remove(&peer_id);
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            } => {
+                tracing::debug!("Failed to reach {peer_id}: {error}");
+                // Only record owners are ever pruned, other peers would pile up in the map
+                let owns_record = self
+                    .swarm
+                    .behaviour_mut()
+                    .kad
+                    .store_mut()
+                    .get(&record::record_key(&peer_id))
+                    .is_some();
+                if owns_record {
+                    self.unreachable_since
+                        .entry(peer_id)
+                        .or_insert_with(Instant::now);
+                }
+            }
+            SwarmEvent::Behaviour(event) => self.on_behaviour_event(event),
+            _ => tracing::debug!("Received unhandled event: {event:?}"),
         }
-        SwarmEvent::Behaviour(event) => on_behaviour_event(swarm, event),
-        _ => tracing::debug!("Received unhandled event: {event:?}"),
     }
-}
 
-fn on_behaviour_event(swarm: &mut Swarm<Behaviour>, event: BehaviourEvent) {
-    match event {
-        BehaviourEvent::Identify(event) => {
-            match event {
-                identify::Event::Received { peer_id, info, .. } => {
-                    tracing::info!("Received identify event with info: {info:?}");
+    fn on_behaviour_event(&mut self, event: BehaviourEvent) {
+        match event {
+            BehaviourEvent::Identify(event) => {
+                match event {
+                    identify::Event::Received { peer_id, info, .. } => {
+                        tracing::info!("Received identify event with info: {info:?}");
 
-                    if info.listen_addrs.is_empty() {
-                        tracing::warn!("No listen addresses for peer {}, skipping...", peer_id);
-                        return;
-                    }
+                        if info.listen_addrs.is_empty() {
+                            tracing::warn!("No listen addresses for peer {}, skipping...", peer_id);
+                            return;
+                        }
 
-                    let is_kad_capable = info
-                        .protocols
-                        .iter()
-                        .any(|stream_protocol| kad::PROTOCOL_NAME.eq(stream_protocol));
+                        let is_kad_capable = info
+                            .protocols
+                            .iter()
+                            .any(|stream_protocol| kad::PROTOCOL_NAME.eq(stream_protocol));
 
-                    if is_kad_capable {
-                        for addr in info.listen_addrs.clone() {
-                            tracing::info!("Adding address to Kademlia: {addr}");
-                            swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                        if is_kad_capable {
+                            for addr in info.listen_addrs {
+                                tracing::info!("Adding address to Kademlia: {addr}");
+                                self.swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                            }
+                        } else {
+                            tracing::warn!("No {} protocol found, skipping...", kad::PROTOCOL_NAME);
                         }
-                    } else {
-                        tracing::warn!("No {} protocol found, skipping...", kad::PROTOCOL_NAME);
+                    }
+                    _ => tracing::debug!("Received unhandled identify event: {event:?}"),
+                };
+            }
+            BehaviourEvent::Relay(relay::Event::ReservationReqAccepted {
+                src_peer_id,
+                renewed: false,
+            }) => tracing::info!("Accepted relay reservation of {src_peer_id}"),
+            BehaviourEvent::Relay(relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            }) => tracing::info!("Relaying {src_peer_id} to {dst_peer_id}"),
+            BehaviourEvent::Dcutr(dcutr::Event {
+                remote_peer_id,
+                result,
+            }) => match result {
+                Ok(_) => tracing::info!("Direct connection to {remote_peer_id} established"),
+                Err(err) => {
+                    tracing::warn!("Connection to {remote_peer_id} stays relayed: {err}")
+                }
+            },
+            BehaviourEvent::Kad(event) => match event {
+                kad::Event::OutboundQueryProgressed { result, .. } => on_query_result(result),
+                kad::Event::InboundRequest { request } => self.on_inbound_request(request),
+                _ => tracing::debug!("Received unhandled kadmelia event: {event:?}"),
+            },
+            _ => tracing::debug!("Received unhandled behaviour event: {event:?}"),
+        }
+    }
+
+    /// Publishes our confirmed external and allowed listen addresses if they changed.
+    ///
+    /// Records are not written on behalf of identified peers, since only they can sign them.
+    fn publish_own_record(&mut self) {
+        let listen_addrs = self.swarm.listeners().cloned().collect();
+        let external_addrs = self.swarm.external_addresses().cloned().collect();
+        self.publisher.update(
+            &mut self.swarm.behaviour_mut().kad,
+            listen_addrs,
+            external_addrs,
+        );
+    }
+
+    /// Dials the owners of stored address records we are not connected to, dropping the records
+    /// of those that have been unreachable for longer than `drop_unreachable_after`.
+    fn probe_record_owners(&mut self) {
+        let local_peer_id = *self.swarm.local_peer_id();
+        let owners = self
+            .swarm
+            .behaviour_mut()
+            .kad
+            .store_mut()
+            .records()
+            .filter_map(|record| record::verify_record(&record).ok())
+            .filter(|address_record| address_record.peer_id() != local_peer_id)
+            .map(|address_record| {
+                let addrs = address_record
+                    .addresses()
+                    .iter()
+                    .map(|address| address.addr.clone())
+                    .collect::<Vec<_>>();
+                (address_record.peer_id(), addrs)
+            })
+            .collect::<Vec<_>>();
+        // Forget owners whose record expired or was replaced in the meantime
+        self.unreachable_since
+            .retain(|peer_id, _| owners.iter().any(|(owner, _)| owner == peer_id));
+
+        for (peer_id, addrs) in owners {
+            if self.swarm.is_connected(&peer_id) {
+                self.unreachable_since.remove(&peer_id);
+                continue;
+            }
+
+            let expired = self
+                .unreachable_since
+                .get(&peer_id)
+                .is_some_and(|since| since.elapsed() >= self.drop_unreachable_after);
+            if expired {
+                tracing::info!("Dropping address record of unreachable peer {peer_id}");
+                self.swarm
+                    .behaviour_mut()
+                    .kad
+                    .store_mut()
+                    .remove(&record::record_key(&peer_id));
+                self.unreachable_since.remove(&peer_id);
+                continue;
+            }
+
+            let opts = DialOpts::peer_id(peer_id).addresses(addrs).build();
+            if let Err(err) = self.swarm.dial(opts) {
+                tracing::debug!("Failed to dial {peer_id}: {err}");
+                self.unreachable_since
+                    .entry(peer_id)
+                    .or_insert_with(Instant::now);
+            }
+        }
+    }
+
+    fn on_inbound_request(&mut self, request: InboundRequest) {
+        match request {
+            kad::InboundRequest::GetRecord { .. } => {
+                tracing::info!("Received GetRecord request: {request:?}")
+            }
+            kad::InboundRequest::PutRecord {
+                source,
+                record: Some(record),
+                ..
+            } => {
+                if let Err(err) = self.validator.validate(&record) {
+                    tracing::warn!("Rejected PutRecord from {source}: {err}");
+                    return;
+                }
+
+                let store = self.swarm.behaviour_mut().kad.store_mut();
+                if let Some(stored) = store.get(&record.key) {
+                    if record::is_stale(&record, &stored) {
+                        tracing::info!("Ignoring stale record from {source}");
                         return;
                     }
+                }
 
-                    tracing::info!("Putting listen addresses for peer: {}", peer_id);
-                    let buffer: Vec<u8> = vec![];
-                    let bytes = cbor4ii::serde::to_vec(buffer, &info.listen_addrs).unwrap();
-                    let record = Record::new(peer_id.as_ref().to_owned(), bytes);
-                    swarm
-                        .behaviour_mut()
-                        .kad
-                        .put_record(record, kad::Quorum::One)
-                        .unwrap();
+                tracing::info!("Storing record from {source}");
+                if let Err(err) = store.put(record) {
+                    tracing::error!("Failed to store record from {source}: {err}");
                 }
-                _ => tracing::debug!("Received unhandled identify event: {event:?}"),
-            };
+            }
+            kad::InboundRequest::AddProvider {
+                record: Some(record),
+            } => {
+                let provider = record.provider;
+                let store = self.swarm.behaviour_mut().kad.store_mut();
+                if let Err(err) = store.add_provider(record) {
+                    tracing::error!("Failed to store provider record from {provider}: {err}");
+                }
+            }
+            _ => tracing::debug!("Received unhandled InboundRequest: {request:?}"),
         }
-        BehaviourEvent::Kad(event) => match event {
-            kad::Event::OutboundQueryProgressed { result, .. } => on_query_result(result),
-            kad::Event::InboundRequest { request } => on_inbound_request(request),
-            _ => tracing::debug!("Received unhandled kadmelia event: {event:?}"),
-        },
-        _ => tracing::debug!("Received unhandled behaviour event: {event:?}"),
     }
 }
 
 fn on_query_result(result: QueryResult) {
     match result {
         kad::QueryResult::GetRecord(get_record_ok) => match get_record_ok {
+            Ok(GetRecordOk::FoundRecord(peer_record)) => {
+                match record::verify_record(&peer_record.record) {
+                    Ok(address_record) => tracing::info!(
+                        "Successful GetRecord: {}::{:?}",
+                        address_record.peer_id(),
+                        address_record.addresses()
+                    ),
+                    Err(err) => tracing::warn!("Rejected GetRecord result: {err}"),
+                }
+            }
             Ok(ok) => tracing::info!("Successful GetRecord: {ok:?}"),
             Err(err) => tracing::error!("Failed GetRecord: {err:?}"),
         },
@@ -192,18 +507,14 @@ fn on_query_result(result: QueryResult) {
             Ok(ok) => tracing::info!("Successful PutRecord: {ok:?}"),
             Err(err) => tracing::error!("Failed PutRecord: {err:?}"),
         },
+        kad::QueryResult::StartProviding(add_provider_ok) => match add_provider_ok {
+            Ok(ok) => tracing::info!("Successful StartProviding: {ok:?}"),
+            Err(err) => tracing::error!("Failed StartProviding: {err:?}"),
+        },
+        kad::QueryResult::GetProviders(get_providers_ok) => match get_providers_ok {
+            Ok(ok) => tracing::info!("Successful GetProviders: {ok:?}"),
+            Err(err) => tracing::error!("Failed GetProviders: {err:?}"),
+        },
         _ => tracing::debug!("Received unhandled QueryResult: {result:?}"),
     }
 }
-
-fn on_inbound_request(request: InboundRequest) {
-    match request {
-        kad::InboundRequest::GetRecord { .. } => {
-            tracing::info!("Received GetRecord request: {request:?}")
-        }
-        kad::InboundRequest::PutRecord { .. } => {
-            tracing::info!("Received PutRecord request: {request:?}")
-        }
-        _ => tracing::debug!("Received unhandled InboundRequest: {request:?}"),
-    }
-}
@@ -0,0 +1,405 @@
+//! A [`RecordStore`] that survives restarts by mirroring its contents to a file.
+//!
+//! Records and provider records are kept in a [`MemoryStore`]. Mutations only mark the store as
+//! dirty, the owner calls [`FileStore::flush`] periodically to write the whole store to disk as a
+//! CBOR snapshot, so bursts of puts cost a single write. Expiry times are saved as wall-clock
+//! timestamps, since [`Instant`]s are meaningless across processes. This is aimed at bootnodes,
+//! whose stores are small enough for rewriting the snapshot to be cheap.
+
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use libp2p::{
+    kad::{
+        store::{self, MemoryStore, MemoryStoreConfig, RecordStore},
+        ProviderRecord, Record, RecordKey,
+    },
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+
+/// How often owners of a [`FileStore`] are expected to call [`FileStore::flush`].
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("failed to read or write the record store: {0}")]
+    Io(#[from] io::Error),
+    #[error("failed to decode the record store: {0}")]
+    Decode(#[from] cbor4ii::serde::DecodeError<std::convert::Infallible>),
+}
+
+/// Record store backed by a file, see the [module documentation](self).
+///
+/// When created without a path nothing is persisted and it behaves like a [`MemoryStore`].
+pub struct FileStore {
+    inner: MemoryStore,
+    /// Keys with at least one provider, [`MemoryStore`] only lists the locally provided ones.
+    provider_keys: HashSet<RecordKey>,
+    path: Option<PathBuf>,
+    /// Whether the store changed since the last [`FileStore::flush`].
+    dirty: bool,
+}
+
+impl FileStore {
+    /// Opens the store at `path`, loading any non-expired records saved by a previous run.
+    pub fn open(
+        local_id: PeerId,
+        config: MemoryStoreConfig,
+        path: Option<PathBuf>,
+    ) -> Result<Self, StoreError> {
+        let mut store = Self {
+            inner: MemoryStore::with_config(local_id, config),
+            provider_keys: HashSet::new(),
+            path: None,
+            dirty: false,
+        };
+
+        if let Some(path) = &path {
+            let snapshot = Snapshot::load(path)?;
+            let (records, providers) = snapshot.into_records();
+            tracing::info!(
+                "Loaded {} records and {} provider records from {}",
+                records.len(),
+                providers.len(),
+                path.display()
+            );
+            for record in records {
+                if let Err(err) = store.put(record) {
+                    tracing::warn!("Dropping persisted record: {err}");
+                }
+            }
+            for provider in providers {
+                if let Err(err) = store.add_provider(provider) {
+                    tracing::warn!("Dropping persisted provider record: {err}");
+                }
+            }
+        }
+
+        store.path = path;
+        store.dirty = false;
+        Ok(store)
+    }
+
+    /// Drops expired records and provider records and, if anything changed since the last call,
+    /// writes the store to disk.
+    pub fn flush(&mut self) -> Result<(), StoreError> {
+        self.remove_expired();
+        if !self.dirty {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.path {
+            Snapshot::from_store(self).save(path)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Kademlia only skips expired entries when answering queries, they stay in the store until
+    /// removed here.
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+
+        let expired_records = self
+            .inner
+            .records()
+            .filter(|record| record.is_expired(now))
+            .map(|record| record.key.clone())
+            .collect::<Vec<_>>();
+        for key in expired_records {
+            self.remove(&key);
+        }
+
+        let expired_providers = self
+            .provider_keys
+            .iter()
+            .flat_map(|key| self.inner.providers(key))
+            .filter(|record| record.is_expired(now))
+            .collect::<Vec<_>>();
+        for record in expired_providers {
+            self.remove_provider(&record.key, &record.provider);
+        }
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            tracing::error!("Failed to persist record store: {err}");
+        }
+    }
+}
+
+impl RecordStore for FileStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> store::Result<()> {
+        self.inner.put(r)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.inner.remove(k);
+        self.dirty = true;
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        let key = record.key.clone();
+        self.inner.add_provider(record)?;
+        self.provider_keys.insert(key);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.inner.remove_provider(k, p);
+        if self.inner.providers(k).is_empty() {
+            self.provider_keys.remove(k);
+        }
+        self.dirty = true;
+    }
+}
+
+/// On-disk representation of the store.
+#[derive(Default, Serialize, Deserialize)]
+struct Snapshot {
+    records: Vec<StoredRecord>,
+    providers: Vec<StoredProviderRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<PeerId>,
+    /// Milliseconds since the UNIX epoch.
+    expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredProviderRecord {
+    key: Vec<u8>,
+    provider: PeerId,
+    addresses: Vec<Multiaddr>,
+    /// Milliseconds since the UNIX epoch.
+    expires: Option<u64>,
+}
+
+impl Snapshot {
+    fn load(path: &Path) -> Result<Self, StoreError> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(cbor4ii::serde::from_slice(&bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let bytes = cbor4ii::serde::to_vec(vec![], self)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        // Write to a temporary file first so a crash never leaves a truncated store behind
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(tmp_path, path)
+    }
+
+    fn from_store(store: &FileStore) -> Self {
+        let clock = Clock::now();
+
+        let records = store
+            .inner
+            .records()
+            .map(|record| StoredRecord {
+                key: record.key.to_vec(),
+                value: record.value.clone(),
+                publisher: record.publisher,
+                expires: record.expires.map(|expires| clock.unix_millis_at(expires)),
+            })
+            .collect();
+
+        let providers = store
+            .provider_keys
+            .iter()
+            .flat_map(|key| store.inner.providers(key))
+            .map(|record| StoredProviderRecord {
+                key: record.key.to_vec(),
+                provider: record.provider,
+                addresses: record.addresses,
+                expires: record.expires.map(|expires| clock.unix_millis_at(expires)),
+            })
+            .collect();
+
+        Self { records, providers }
+    }
+
+    /// Converts the snapshot back into records, dropping the ones that expired in the meantime.
+    fn into_records(self) -> (Vec<Record>, Vec<ProviderRecord>) {
+        let clock = Clock::now();
+
+        let records = self
+            .records
+            .into_iter()
+            .filter_map(|stored| {
+                let expires = clock.instant_at(stored.expires)?;
+                Some(Record {
+                    key: RecordKey::from(stored.key),
+                    value: stored.value,
+                    publisher: stored.publisher,
+                    expires,
+                })
+            })
+            .collect();
+
+        let providers = self
+            .providers
+            .into_iter()
+            .filter_map(|stored| {
+                let expires = clock.instant_at(stored.expires)?;
+                Some(ProviderRecord {
+                    key: RecordKey::from(stored.key),
+                    provider: stored.provider,
+                    expires,
+                    addresses: stored.addresses,
+                })
+            })
+            .collect();
+
+        (records, providers)
+    }
+}
+
+/// Pairs the monotonic and wall clocks to convert between them.
+struct Clock {
+    instant: Instant,
+    system: SystemTime,
+}
+
+impl Clock {
+    fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            system: SystemTime::now(),
+        }
+    }
+
+    fn unix_millis_at(&self, instant: Instant) -> u64 {
+        let system = if instant >= self.instant {
+            self.system + (instant - self.instant)
+        } else {
+            self.system - (self.instant - instant)
+        };
+        system
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    /// Returns `None` if the timestamp is in the past, `Some(None)` if there is no expiry.
+    fn instant_at(&self, millis: Option<u64>) -> Option<Option<Instant>> {
+        let Some(millis) = millis else {
+            return Some(None);
+        };
+        let system = SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let remaining = system.duration_since(self.system).ok()?;
+        Some(Some(self.instant + remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_round_trip() {
+        let clock = Clock::now();
+        let expires = clock.instant + Duration::from_secs(3600);
+
+        let millis = clock.unix_millis_at(expires);
+        let restored = clock.instant_at(Some(millis)).unwrap().unwrap();
+        // Milliseconds are the resolution of the snapshot
+        assert!(expires.duration_since(restored) < Duration::from_millis(1));
+    }
+
+    #[test]
+    fn clock_drops_past_timestamps() {
+        let clock = Clock::now();
+        let expired = clock.unix_millis_at(clock.instant - Duration::from_secs(1));
+
+        assert_eq!(clock.instant_at(Some(expired)), None);
+        assert_eq!(clock.instant_at(None), Some(None));
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let local_id = PeerId::random();
+        let path = std::env::temp_dir().join(format!("lp2p-store-{local_id}.cbor"));
+        let record = Record {
+            key: RecordKey::new(&"key"),
+            value: b"value".to_vec(),
+            publisher: Some(PeerId::random()),
+            expires: Some(Instant::now() + Duration::from_secs(3600)),
+        };
+        let expired = Record {
+            key: RecordKey::new(&"expired"),
+            value: b"value".to_vec(),
+            publisher: None,
+            expires: Some(Instant::now() - Duration::from_secs(1)),
+        };
+
+        let mut store = FileStore::open(local_id, Default::default(), Some(path.clone())).unwrap();
+        store.put(record.clone()).unwrap();
+        store.put(expired.clone()).unwrap();
+        store.flush().unwrap();
+
+        let store = FileStore::open(local_id, Default::default(), Some(path.clone())).unwrap();
+        fs::remove_file(&path).unwrap();
+        let stored = store.get(&record.key).unwrap();
+        assert_eq!(stored.value, record.value);
+        assert_eq!(stored.publisher, record.publisher);
+        assert!(store.get(&expired.key).is_none());
+    }
+
+    #[test]
+    fn flush_prunes_expired_providers() {
+        let local_id = PeerId::random();
+        let mut store = FileStore::open(local_id, Default::default(), None).unwrap();
+        let key = RecordKey::new(&"key");
+        store
+            .add_provider(ProviderRecord {
+                key: key.clone(),
+                provider: PeerId::random(),
+                expires: Some(Instant::now() - Duration::from_secs(1)),
+                addresses: vec![],
+            })
+            .unwrap();
+
+        store.flush().unwrap();
+        assert!(store.providers(&key).is_empty());
+        assert!(store.provider_keys.is_empty());
+    }
+}
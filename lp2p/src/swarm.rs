@@ -0,0 +1,301 @@
+//! Assembly of the network stack shared by all binaries and the wasm crate.
+//!
+//! Every node speaks noise over yamux, identifies itself with [`IDENTIFY_PROTOCOL`] and, when
+//! running natively, listens on and dials TCP, WebSockets and QUIC, which brings its own
+//! encryption and multiplexing. Nodes given a certificate also accept webrtc-direct, which
+//! browsers can dial without the listener having a TLS certificate signed by a CA. In the browser
+//! the available transports are `websocket-websys`, `webtransport-websys` and `webrtc-websys`.
+//! Swarms created with a relay client can also dial and listen on `/p2p-circuit` addresses
+//! through a relay.
+
+use std::time::Duration;
+
+use libp2p::{
+    autonat,
+    core::{self, muxing::StreamMuxerBox, transport::Boxed},
+    futures::future::Either,
+    identify,
+    identity::Keypair,
+    kad::{self, store::RecordStore},
+    noise, relay,
+    swarm::{self, NetworkBehaviour},
+    yamux, Multiaddr, PeerId, Swarm, Transport,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use libp2p::{futures::channel::mpsc, websocket::tls};
+#[cfg(not(target_arch = "wasm32"))]
+use libp2p::{quic, tcp, websocket};
+#[cfg(target_arch = "wasm32")]
+use libp2p::{websocket_websys, webtransport_websys};
+#[cfg(not(target_arch = "wasm32"))]
+use libp2p_webrtc::tokio::Certificate;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::tls::{ReloadableTls, TlsReloader};
+use crate::{extract_peer_id, Error};
+
+/// Protocol version announced through identify.
+pub const IDENTIFY_PROTOCOL: &str = "/polka-test/identify/1.0.0";
+
+/// Knobs for the network stack, the defaults are what all our nodes run with.
+#[derive(Debug, Clone)]
+pub struct SwarmConfig {
+    pub identify_protocol: String,
+    pub kad_mode: kad::Mode,
+    pub kad: kad::Config,
+    pub idle_connection_timeout: Duration,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub tcp: bool,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub websocket: bool,
+    /// QUIC v1, dialed and listened on through `/udp/<port>/quic-v1` addresses.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub quic: bool,
+    /// Enables webrtc-direct, listened on through `/udp/<port>/webrtc-direct` addresses, with
+    /// the given certificate.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub webrtc_certificate: Option<Certificate>,
+}
+
+impl Default for SwarmConfig {
+    fn default() -> Self {
+        Self {
+            identify_protocol: IDENTIFY_PROTOCOL.to_string(),
+            kad_mode: kad::Mode::Client,
+            kad: kad::Config::new(kad::PROTOCOL_NAME),
+            idle_connection_timeout: Duration::from_secs(10),
+            #[cfg(not(target_arch = "wasm32"))]
+            tcp: true,
+            #[cfg(not(target_arch = "wasm32"))]
+            websocket: true,
+            #[cfg(not(target_arch = "wasm32"))]
+            quic: true,
+            #[cfg(not(target_arch = "wasm32"))]
+            webrtc_certificate: None,
+        }
+    }
+}
+
+/// Builds swarms and the protocol behaviours whose configuration must match across nodes.
+///
+/// ```ignore
+/// let builder = SwarmBuilder::new(keypair, SwarmConfig::default());
+/// let behaviour = Behaviour {
+///     identify: builder.identify(),
+///     kad: builder.kad(MemoryStore::new(builder.local_peer_id()), bootnodes)?,
+/// };
+/// let swarm = builder.build(behaviour)?;
+/// ```
+pub struct SwarmBuilder {
+    keypair: Keypair,
+    config: SwarmConfig,
+    /// Set once [`SwarmBuilder::relay_client`] created the matching behaviour.
+    relay_transport: Option<relay::client::Transport>,
+    /// Set by [`SwarmBuilder::websocket_tls`].
+    #[cfg(not(target_arch = "wasm32"))]
+    websocket_tls: Option<(tls::Config, mpsc::UnboundedReceiver<tls::Config>)>,
+}
+
+impl SwarmBuilder {
+    pub fn new(keypair: Keypair, config: SwarmConfig) -> Self {
+        Self {
+            keypair,
+            config,
+            relay_transport: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            websocket_tls: None,
+        }
+    }
+
+    pub fn keypair(&self) -> &Keypair {
+        &self.keypair
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        self.keypair.public().to_peer_id()
+    }
+
+    pub fn identify(&self) -> identify::Behaviour {
+        identify::Behaviour::new(identify::Config::new(
+            self.config.identify_protocol.clone(),
+            self.keypair.public(),
+        ))
+    }
+
+    /// Creates the Kademlia behaviour, seeding its routing table with `bootnodes`.
+    ///
+    /// Every bootnode address must end with a `/p2p/<peer-id>` segment.
+    pub fn kad<S: RecordStore + Send + 'static>(
+        &self,
+        store: S,
+        bootnodes: Vec<Multiaddr>,
+    ) -> Result<kad::Behaviour<S>, Error> {
+        let mut kad =
+            kad::Behaviour::with_config(self.local_peer_id(), store, self.config.kad.clone());
+        kad.set_mode(Some(self.config.kad_mode));
+
+        for node in bootnodes {
+            let peer_id =
+                extract_peer_id(&node).ok_or_else(|| Error::MissingPeerId(node.clone()))?;
+            tracing::info!("Adding address to Kademlia: {node}");
+            kad.add_address(&peer_id, node);
+        }
+
+        Ok(kad)
+    }
+
+    /// Creates an AutoNAT behaviour probing through `bootnodes` in addition to connected peers,
+    /// so probes do not depend on a connection being open at the time.
+    pub fn autonat(&self, config: autonat::Config, bootnodes: &[Multiaddr]) -> autonat::Behaviour {
+        let mut autonat = autonat::Behaviour::new(self.local_peer_id(), config);
+        for node in bootnodes {
+            if let Some(peer_id) = extract_peer_id(node) {
+                autonat.add_server(peer_id, Some(node.clone()));
+            }
+        }
+        autonat
+    }
+
+    /// Creates the relay client behaviour, the swarm built afterwards can then reach peers through
+    /// `/p2p-circuit` addresses and reserve a slot on a relay by listening on
+    /// `<relay-addr>/p2p-circuit`.
+    pub fn relay_client(&mut self) -> relay::client::Behaviour {
+        let (transport, behaviour) = relay::client::new(self.local_peer_id());
+        self.relay_transport = Some(transport);
+        behaviour
+    }
+
+    /// Enables `/tls/ws` listen addresses with the certificate in `config`, the returned
+    /// [`TlsReloader`] replaces it while the swarm is running.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn websocket_tls(&mut self, config: tls::Config) -> TlsReloader {
+        let (reloader, updates) = TlsReloader::new();
+        self.websocket_tls = Some((config, updates));
+        reloader
+    }
+
+    pub fn build<B: NetworkBehaviour>(mut self, behaviour: B) -> Result<Swarm<B>, Error> {
+        let local_peer_id = self.local_peer_id();
+        tracing::info!("Local peer id: {local_peer_id}");
+
+        Ok(Swarm::new(
+            self.transport()?,
+            behaviour,
+            local_peer_id,
+            executor_config().with_idle_connection_timeout(self.config.idle_connection_timeout),
+        ))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn transport(&mut self) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Error> {
+        let mut transports = self.relay_transport()?.into_iter().collect::<Vec<_>>();
+
+        if self.config.tcp {
+            transports.push(
+                tcp::tokio::Transport::new(tcp::Config::new())
+                    .upgrade(core::upgrade::Version::V1Lazy)
+                    .authenticate(noise::Config::new(&self.keypair)?)
+                    .multiplex(yamux::Config::default())
+                    .boxed(),
+            );
+        }
+
+        if self.config.websocket {
+            let mut websocket =
+                websocket::WsConfig::new(tcp::tokio::Transport::new(tcp::Config::new()));
+            let tls_updates = self.websocket_tls.take().map(|(config, updates)| {
+                websocket.set_tls_config(config);
+                updates
+            });
+            transports.push(
+                ReloadableTls::new(websocket, tls_updates)
+                    .upgrade(core::upgrade::Version::V1Lazy)
+                    .authenticate(noise::Config::new(&self.keypair)?)
+                    .multiplex(yamux::Config::default())
+                    .boxed(),
+            );
+        }
+
+        if self.config.quic {
+            transports.push(
+                quic::tokio::Transport::new(quic::Config::new(&self.keypair))
+                    .map(|(peer_id, connection), _| (peer_id, StreamMuxerBox::new(connection)))
+                    .boxed(),
+            );
+        }
+
+        if let Some(certificate) = &self.config.webrtc_certificate {
+            transports.push(
+                libp2p_webrtc::tokio::Transport::new(self.keypair.clone(), certificate.clone())
+                    .map(|(peer_id, connection), _| (peer_id, StreamMuxerBox::new(connection)))
+                    .boxed(),
+            );
+        }
+
+        combine(transports)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn transport(&mut self) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Error> {
+        let mut transports = self.relay_transport()?.into_iter().collect::<Vec<_>>();
+        transports.push(
+            websocket_websys::Transport::default()
+                .upgrade(core::upgrade::Version::V1Lazy)
+                .authenticate(noise::Config::new(&self.keypair)?)
+                .multiplex(yamux::Config::default())
+                .boxed(),
+        );
+        transports.push(
+            webtransport_websys::Transport::new(webtransport_websys::Config::new(&self.keypair))
+                .boxed(),
+        );
+        transports.push(
+            libp2p_webrtc_websys::Transport::new(libp2p_webrtc_websys::Config::new(&self.keypair))
+                .boxed(),
+        );
+
+        combine(transports)
+    }
+
+    /// Upgrades the relay client transport, if any. It goes first so `/p2p-circuit` addresses are
+    /// always dialed through the relay.
+    fn relay_transport(&mut self) -> Result<Option<Boxed<(PeerId, StreamMuxerBox)>>, Error> {
+        let Some(transport) = self.relay_transport.take() else {
+            return Ok(None);
+        };
+
+        Ok(Some(
+            transport
+                .upgrade(core::upgrade::Version::V1Lazy)
+                .authenticate(noise::Config::new(&self.keypair)?)
+                .multiplex(yamux::Config::default())
+                .boxed(),
+        ))
+    }
+}
+
+/// Joins the transports in order, the first one supporting an address is used to dial it.
+fn combine(
+    transports: Vec<Boxed<(PeerId, StreamMuxerBox)>>,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Error> {
+    transports
+        .into_iter()
+        .reduce(|acc, transport| {
+            acc.or_transport(transport)
+                .map(|output, _| match output {
+                    Either::Left(output) | Either::Right(output) => output,
+                })
+                .boxed()
+        })
+        .ok_or(Error::NoTransport)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn executor_config() -> swarm::Config {
+    swarm::Config::with_tokio_executor()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn executor_config() -> swarm::Config {
+    swarm::Config::with_wasm_executor()
+}
@@ -0,0 +1,197 @@
+//! TLS for secure WebSocket (`/tls/ws`) listeners.
+//!
+//! Browsers on https pages refuse plain `ws` connections, so servers can also listen on `/tls/ws`
+//! addresses with a certificate read from PEM files, or a self-signed one when testing locally.
+//! The certificate can be replaced through a [`TlsReloader`] while the swarm is running, new
+//! connections are accepted with it while established ones are left alone.
+
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use libp2p::{
+    core::transport::{DialOpts, ListenerId, TransportError, TransportEvent},
+    futures::{channel::mpsc, AsyncRead, AsyncWrite, StreamExt},
+    websocket::{self, tls},
+    Multiaddr, Transport,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("failed to read {}: {source}", path.display())]
+    Pem {
+        path: PathBuf,
+        source: rustls_pki_types::pem::Error,
+    },
+    #[error("{} does not contain a certificate", path.display())]
+    NoCertificate { path: PathBuf },
+    #[error("invalid TLS certificate or key: {0}")]
+    Config(#[from] tls::Error),
+}
+
+/// Command line arguments selecting the certificate of `/tls/ws` listen addresses.
+#[derive(Debug, Clone, clap::Args)]
+pub struct TlsArgs {
+    /// PEM certificate chain for `/tls/ws` listen addresses, read again together with `--tls-key`
+    /// when the process receives SIGHUP.
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve `/tls/ws` with a certificate for `localhost` generated on startup, only meant for
+    /// local testing.
+    #[arg(long, conflicts_with = "tls_cert")]
+    pub tls_self_signed: bool,
+}
+
+impl TlsArgs {
+    /// Returns the selected TLS configuration, `None` if `/tls/ws` is not enabled.
+    pub fn config(&self) -> Result<Option<tls::Config>, TlsError> {
+        if let Some((cert, key)) = self.files() {
+            return load(cert, key).map(Some);
+        }
+        if self.tls_self_signed {
+            return Ok(Some(self_signed()));
+        }
+        Ok(None)
+    }
+
+    /// The certificate and key files, if the certificate is read from files.
+    pub fn files(&self) -> Option<(&Path, &Path)> {
+        Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?))
+    }
+}
+
+/// Reads a PEM certificate chain and the PEM private key belonging to its first certificate.
+pub fn load(cert_path: &Path, key_path: &Path) -> Result<tls::Config, TlsError> {
+    let pem_error = |path: &Path| {
+        let path = path.to_owned();
+        move |source| TlsError::Pem { path, source }
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(pem_error(cert_path))?
+        .map(|cert| cert.map(|cert| tls::Certificate::new(cert.to_vec())))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem_error(cert_path))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate {
+            path: cert_path.to_owned(),
+        });
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(pem_error(key_path))?;
+
+    Ok(tls::Config::new(
+        tls::PrivateKey::new(key.secret_der().to_vec()),
+        certs,
+    )?)
+}
+
+/// Generates a self-signed certificate for `localhost`, `127.0.0.1` and `::1`. Browsers only
+/// accept it after an exception was added, e.g. by opening `https://localhost:<port>` once.
+pub fn self_signed() -> tls::Config {
+    let names = ["localhost", "127.0.0.1", "::1"].map(String::from).to_vec();
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(names).expect("the names are valid SANs");
+    tracing::warn!("Serving /tls/ws with a self-signed certificate");
+
+    tls::Config::new(
+        tls::PrivateKey::new(key_pair.serialize_der()),
+        [tls::Certificate::new(cert.der().to_vec())],
+    )
+    .expect("the generated key matches the certificate")
+}
+
+/// Replaces the certificate of the `/tls/ws` listeners of a running swarm, see
+/// [`SwarmBuilder::websocket_tls`](crate::swarm::SwarmBuilder::websocket_tls).
+#[derive(Debug, Clone)]
+pub struct TlsReloader(mpsc::UnboundedSender<tls::Config>);
+
+impl TlsReloader {
+    pub(crate) fn new() -> (Self, mpsc::UnboundedReceiver<tls::Config>) {
+        let (sender, receiver) = mpsc::unbounded();
+        (Self(sender), receiver)
+    }
+
+    pub fn reload(&self, config: tls::Config) {
+        // The receiver only goes away together with the swarm
+        let _ = self.0.unbounded_send(config);
+    }
+}
+
+/// A WebSocket transport whose TLS configuration is replaced by the ones sent through a
+/// [`TlsReloader`]. The transport reads its configuration for every accepted connection.
+pub(crate) struct ReloadableTls<T: Transport>
+where
+    T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    inner: websocket::WsConfig<T>,
+    updates: Option<mpsc::UnboundedReceiver<tls::Config>>,
+}
+
+impl<T: Transport> ReloadableTls<T>
+where
+    T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    pub(crate) fn new(
+        inner: websocket::WsConfig<T>,
+        updates: Option<mpsc::UnboundedReceiver<tls::Config>>,
+    ) -> Self {
+        Self { inner, updates }
+    }
+}
+
+impl<T> Transport for ReloadableTls<T>
+where
+    T: Transport + Send + Unpin + 'static,
+    T::Error: Send + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Output = <websocket::WsConfig<T> as Transport>::Output;
+    type Error = <websocket::WsConfig<T> as Transport>::Error;
+    type ListenerUpgrade = <websocket::WsConfig<T> as Transport>::ListenerUpgrade;
+    type Dial = <websocket::WsConfig<T> as Transport>::Dial;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        self.inner.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        opts: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.inner.dial(addr, opts)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        let this = &mut *self;
+        if let Some(updates) = &mut this.updates {
+            while let Poll::Ready(Some(config)) = updates.poll_next_unpin(cx) {
+                tracing::info!("Reloaded the TLS certificate");
+                this.inner.set_tls_config(config);
+            }
+        }
+
+        Pin::new(&mut this.inner).poll(cx)
+    }
+}
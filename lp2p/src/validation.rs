@@ -0,0 +1,148 @@
+//! Validation of records received from remote peers before they reach the record store.
+
+use libp2p::kad;
+
+use crate::record::{self, RecordError};
+
+/// Default upper bound for the size of a record value, in bytes.
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 4 * 1024;
+
+/// Default upper bound for the number of addresses in a single record.
+pub const DEFAULT_MAX_ADDRESSES: usize = 32;
+
+/// Prefix of the keys whose values are stored as they are, e.g. to seed the DHT when debugging.
+/// Address records are keyed by the bytes of a peer id, which never start with it.
+pub const RAW_KEY_PREFIX: &[u8] = b"/raw/";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ValidationError {
+    #[error("record value is {size} bytes, the limit is {max} bytes")]
+    ValueTooLarge { size: usize, max: usize },
+    #[error("record holds {count} addresses, the limit is {max}")]
+    TooManyAddresses { count: usize, max: usize },
+    #[error(transparent)]
+    InvalidRecord(#[from] RecordError),
+}
+
+/// Decides whether a record received through an inbound `PutRecord` request may be stored.
+pub trait RecordValidator {
+    fn validate(&self, record: &kad::Record) -> Result<(), ValidationError>;
+}
+
+/// Accepts signed address records, as produced by [`record::new_record`], and arbitrary values
+/// under keys starting with [`RAW_KEY_PREFIX`], within the configured size limits.
+#[derive(Debug, Clone)]
+pub struct PeerRecordValidator {
+    pub max_value_size: usize,
+    pub max_addresses: usize,
+}
+
+impl Default for PeerRecordValidator {
+    fn default() -> Self {
+        Self {
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            max_addresses: DEFAULT_MAX_ADDRESSES,
+        }
+    }
+}
+
+impl RecordValidator for PeerRecordValidator {
+    fn validate(&self, record: &kad::Record) -> Result<(), ValidationError> {
+        // Check the size before decoding anything, so we don't spend time on oversized garbage
+        if record.value.len() > self.max_value_size {
+            return Err(ValidationError::ValueTooLarge {
+                size: record.value.len(),
+                max: self.max_value_size,
+            });
+        }
+
+        if record.key.as_ref().starts_with(RAW_KEY_PREFIX) {
+            return Ok(());
+        }
+
+        let address_record = record::verify_record(record)?;
+        if address_record.addresses().len() > self.max_addresses {
+            return Err(ValidationError::TooManyAddresses {
+                count: address_record.addresses().len(),
+                max: self.max_addresses,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity::Keypair;
+
+    use super::*;
+
+    fn record_with_addresses(count: u16) -> kad::Record {
+        let addrs = (0..count)
+            .map(|port| record::Address {
+                addr: format!("/ip4/192.0.2.1/tcp/{port}").parse().unwrap(),
+                confirmed: false,
+            })
+            .collect();
+        record::new_record(&Keypair::generate_ed25519(), 1, addrs).unwrap()
+    }
+
+    #[test]
+    fn accepts_record_within_limits() {
+        let validator = PeerRecordValidator::default();
+        assert!(validator.validate(&record_with_addresses(2)).is_ok());
+    }
+
+    #[test]
+    fn rejects_oversized_value() {
+        let validator = PeerRecordValidator {
+            max_value_size: 64,
+            ..Default::default()
+        };
+        let record = record_with_addresses(8);
+
+        assert!(matches!(
+            validator.validate(&record),
+            Err(ValidationError::ValueTooLarge { size, max: 64 }) if size == record.value.len()
+        ));
+    }
+
+    #[test]
+    fn rejects_too_many_addresses() {
+        let validator = PeerRecordValidator {
+            max_addresses: 2,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            validator.validate(&record_with_addresses(3)),
+            Err(ValidationError::TooManyAddresses { count: 3, max: 2 })
+        ));
+    }
+
+    #[test]
+    fn accepts_raw_value_under_prefix() {
+        let validator = PeerRecordValidator::default();
+        let record = kad::Record::new(kad::RecordKey::new(&"/raw/foo"), b"bar".to_vec());
+        assert!(validator.validate(&record).is_ok());
+
+        let oversized = kad::Record::new(kad::RecordKey::new(&"/raw/foo"), vec![0; 5000]);
+        assert!(matches!(
+            validator.validate(&oversized),
+            Err(ValidationError::ValueTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_unsigned_value() {
+        let validator = PeerRecordValidator::default();
+        let peer_id = Keypair::generate_ed25519().public().to_peer_id();
+        let record = kad::Record::new(record::record_key(&peer_id), b"foo".to_vec());
+
+        assert!(matches!(
+            validator.validate(&record),
+            Err(ValidationError::InvalidRecord(_))
+        ));
+    }
+}
@@ -0,0 +1,305 @@
+//! Hole punching through a relay, on loopback TCP.
+//!
+//! Both clients sit behind a simulated NAT: their transport drops inbound connections from
+//! addresses they have not dialed themselves. The dialing client can therefore only reach the
+//! listening one through the relay at first, and a direct connection only comes up once DCUtR had
+//! both sides dial each other.
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use libp2p::{
+    core::{
+        self,
+        transport::{DialOpts, ListenerId, PortUse, TransportError, TransportEvent},
+        ConnectedPoint, Endpoint,
+    },
+    dcutr,
+    futures::StreamExt,
+    identify,
+    identity::Keypair,
+    multiaddr::Protocol,
+    noise, relay,
+    swarm::{self, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, Swarm, Transport,
+};
+use lp2p::swarm::{SwarmBuilder, SwarmConfig};
+
+#[derive(NetworkBehaviour)]
+struct Relay {
+    identify: identify::Behaviour,
+    relay: relay::Behaviour,
+}
+
+#[derive(NetworkBehaviour)]
+struct Client {
+    identify: identify::Behaviour,
+    relay_client: relay::client::Behaviour,
+    dcutr: dcutr::Behaviour,
+}
+
+fn builder() -> SwarmBuilder {
+    SwarmBuilder::new(
+        Keypair::generate_ed25519(),
+        SwarmConfig {
+            websocket: false,
+            ..Default::default()
+        },
+    )
+}
+
+fn relay() -> Swarm<Relay> {
+    let builder = builder();
+    let behaviour = Relay {
+        identify: builder.identify(),
+        relay: relay::Behaviour::new(builder.local_peer_id(), relay::Config::default()),
+    };
+    builder.build(behaviour).unwrap()
+}
+
+/// A peer with a public address, used to check the NAT from outside.
+fn outsider() -> Swarm<identify::Behaviour> {
+    let builder = builder();
+    let behaviour = builder.identify();
+    builder.build(behaviour).unwrap()
+}
+
+/// Wraps the TCP transport like a NAT would: inbound connections are only let through from
+/// addresses which were dialed before.
+struct Nat {
+    inner: tcp::tokio::Transport,
+    dialed: Arc<Mutex<HashSet<SocketAddr>>>,
+}
+
+impl Nat {
+    fn new() -> Self {
+        Self {
+            inner: tcp::tokio::Transport::new(tcp::Config::new()),
+            dialed: Default::default(),
+        }
+    }
+}
+
+fn socket_addr(addr: &Multiaddr) -> Option<SocketAddr> {
+    let mut ip = None;
+    for protocol in addr {
+        match protocol {
+            Protocol::Ip4(addr) => ip = Some(IpAddr::V4(addr)),
+            Protocol::Ip6(addr) => ip = Some(IpAddr::V6(addr)),
+            Protocol::Tcp(port) => return ip.map(|ip| SocketAddr::new(ip, port)),
+            _ => {}
+        }
+    }
+    None
+}
+
+impl Transport for Nat {
+    type Output = <tcp::tokio::Transport as Transport>::Output;
+    type Error = <tcp::tokio::Transport as Transport>::Error;
+    type ListenerUpgrade = <tcp::tokio::Transport as Transport>::ListenerUpgrade;
+    type Dial = <tcp::tokio::Transport as Transport>::Dial;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        self.inner.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        opts: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        if let Some(socket_addr) = socket_addr(&addr) {
+            self.dialed.lock().unwrap().insert(socket_addr);
+        }
+        // Loopback has no simultaneous open, the hole punching dial of the listening side goes
+        // out from a new port so that it does not take the four-tuple the other side dials from
+        let opts = match opts.role {
+            Endpoint::Listener => DialOpts {
+                port_use: PortUse::New,
+                ..opts
+            },
+            Endpoint::Dialer => opts,
+        };
+        self.inner.dial(addr, opts)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        loop {
+            let event = match Pin::new(&mut self.inner).poll(cx) {
+                Poll::Ready(event) => event,
+                Poll::Pending => return Poll::Pending,
+            };
+            let TransportEvent::Incoming {
+                listener_id,
+                upgrade,
+                local_addr,
+                send_back_addr,
+            } = event
+            else {
+                return Poll::Ready(event);
+            };
+            let dialed = socket_addr(&send_back_addr)
+                .is_some_and(|addr| self.dialed.lock().unwrap().contains(&addr));
+            if dialed {
+                return Poll::Ready(TransportEvent::Incoming {
+                    listener_id,
+                    upgrade,
+                    local_addr,
+                    send_back_addr,
+                });
+            }
+            // Reset rather than close the connection, a closed one would linger in TIME_WAIT and
+            // keep the hole punching dial to the same address from going through
+            if let Ok(stream) = upgrade.into_inner() {
+                let _ = stream.0.set_linger(Some(Duration::ZERO));
+            }
+        }
+    }
+}
+
+/// A client whose only direct transport is TCP behind a [`Nat`].
+fn client() -> Swarm<Client> {
+    let keypair = Keypair::generate_ed25519();
+    let local_peer_id = keypair.public().to_peer_id();
+    let (relay_transport, relay_client) = relay::client::new(local_peer_id);
+    let transport = relay_transport
+        .or_transport(Nat::new())
+        .upgrade(core::upgrade::Version::V1Lazy)
+        .authenticate(noise::Config::new(&keypair).unwrap())
+        .multiplex(yamux::Config::default())
+        .boxed();
+
+    let behaviour = Client {
+        identify: SwarmBuilder::new(keypair, SwarmConfig::default()).identify(),
+        relay_client,
+        dcutr: dcutr::Behaviour::new(local_peer_id),
+    };
+    Swarm::new(
+        transport,
+        behaviour,
+        local_peer_id,
+        swarm::Config::with_tokio_executor().with_idle_connection_timeout(Duration::from_secs(10)),
+    )
+}
+
+async fn listen<B: NetworkBehaviour>(swarm: &mut Swarm<B>) -> Multiaddr {
+    swarm
+        .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+            return address;
+        }
+    }
+}
+
+#[tokio::test]
+async fn relayed_connection_is_upgraded() {
+    let mut relay = relay();
+    let mut dst = client();
+    let mut src = client();
+    let mut outsider = outsider();
+
+    // Relays only grant reservations once they know an external address of their own
+    let relay_addr = listen(&mut relay).await;
+    relay.add_external_address(relay_addr.clone());
+    let dst_addr = listen(&mut dst).await;
+    listen(&mut src).await;
+
+    let relay_peer_id = *relay.local_peer_id();
+    let dst_peer_id = *dst.local_peer_id();
+    tokio::spawn(relay.collect::<Vec<_>>());
+
+    let circuit_addr = relay_addr
+        .with(Protocol::P2p(relay_peer_id))
+        .with(Protocol::P2pCircuit);
+    dst.listen_on(circuit_addr.clone()).unwrap();
+    loop {
+        if let SwarmEvent::Behaviour(ClientEvent::RelayClient(
+            relay::client::Event::ReservationReqAccepted { .. },
+        )) = dst.select_next_some().await
+        {
+            break;
+        }
+    }
+    tokio::spawn(dst.collect::<Vec<_>>());
+
+    // The NAT keeps the destination out of reach of a direct dial
+    outsider
+        .dial(dst_addr.clone().with(Protocol::P2p(dst_peer_id)))
+        .unwrap();
+    loop {
+        match outsider.select_next_some().await {
+            SwarmEvent::OutgoingConnectionError { .. } => break,
+            SwarmEvent::ConnectionEstablished { endpoint, .. } => {
+                panic!("dialed {} through the NAT", endpoint.get_remote_address())
+            }
+            _ => {}
+        }
+    }
+
+    src.dial(circuit_addr.with(Protocol::P2p(dst_peer_id)))
+        .unwrap();
+    let upgrade = async {
+        let mut connections = vec![];
+        loop {
+            match src.select_next_some().await {
+                SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    connection_id,
+                    endpoint,
+                    ..
+                } if peer_id == dst_peer_id => connections.push((connection_id, endpoint)),
+                SwarmEvent::Behaviour(ClientEvent::Dcutr(dcutr::Event {
+                    remote_peer_id,
+                    result,
+                })) => {
+                    assert_eq!(remote_peer_id, dst_peer_id);
+                    let connection_id = result.expect("hole punching succeeds");
+                    return (connection_id, connections);
+                }
+                _ => {}
+            }
+        }
+    };
+    let (connection_id, connections) = tokio::time::timeout(Duration::from_secs(30), upgrade)
+        .await
+        .expect("DCUtR finishes in time");
+
+    let (_, first) = connections.first().expect("a connection was established");
+    assert!(
+        is_relayed(first),
+        "the first connection goes through the relay"
+    );
+    let (_, endpoint) = connections
+        .iter()
+        .find(|(id, _)| *id == connection_id)
+        .expect("the upgraded connection was established");
+    assert!(!is_relayed(endpoint), "the upgraded connection is direct");
+    let remote = endpoint.get_remote_address();
+    assert_eq!(remote.iter().next(), dst_addr.iter().next());
+}
+
+fn is_relayed(endpoint: &ConnectedPoint) -> bool {
+    endpoint
+        .get_remote_address()
+        .iter()
+        .any(|protocol| protocol == Protocol::P2pCircuit)
+}
@@ -0,0 +1,134 @@
+//! Two swarms built by [`SwarmBuilder`] connecting to each other over every native transport, with
+//! and without the relay client transport in front of it.
+
+use std::time::Duration;
+
+use libp2p::{
+    futures::{future, StreamExt},
+    identify,
+    identity::Keypair,
+    multiaddr::Protocol,
+    relay,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    Multiaddr, Swarm,
+};
+use lp2p::swarm::{SwarmBuilder, SwarmConfig};
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    identify: identify::Behaviour,
+    relay_client: Toggle<relay::client::Behaviour>,
+}
+
+fn swarm(config: SwarmConfig, relay_client: bool) -> Swarm<Behaviour> {
+    let mut builder = SwarmBuilder::new(Keypair::generate_ed25519(), config);
+    let behaviour = Behaviour {
+        identify: builder.identify(),
+        relay_client: relay_client.then(|| builder.relay_client()).into(),
+    };
+    builder.build(behaviour).unwrap()
+}
+
+/// Listens on `listen_addr` with one swarm, dials it from another and waits for both ends of the
+/// connection. Both swarms get a relay client if `relay_client` is set.
+async fn connect(config: SwarmConfig, relay_client: bool, listen_addr: &str) {
+    let mut listener = swarm(config.clone(), relay_client);
+    let mut dialer = swarm(config, relay_client);
+
+    listener.listen_on(listen_addr.parse().unwrap()).unwrap();
+    let addr: Multiaddr = loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = listener.select_next_some().await {
+            break address;
+        }
+    };
+    let listener_peer_id = *listener.local_peer_id();
+    let dialer_peer_id = *dialer.local_peer_id();
+    dialer
+        .dial(addr.with(Protocol::P2p(listener_peer_id)))
+        .unwrap();
+
+    let listener_connected = async {
+        loop {
+            if let SwarmEvent::ConnectionEstablished { peer_id, .. } =
+                listener.select_next_some().await
+            {
+                assert_eq!(peer_id, dialer_peer_id);
+                return;
+            }
+        }
+    };
+    let dialer_connected = async {
+        loop {
+            match dialer.select_next_some().await {
+                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                    assert_eq!(peer_id, listener_peer_id);
+                    return;
+                }
+                SwarmEvent::OutgoingConnectionError { error, .. } => {
+                    panic!("failed to dial {listen_addr}: {error}")
+                }
+                _ => {}
+            }
+        }
+    };
+    tokio::time::timeout(
+        Duration::from_secs(10),
+        future::join(listener_connected, dialer_connected),
+    )
+    .await
+    .expect("the swarms connect in time");
+}
+
+fn tcp_only() -> SwarmConfig {
+    SwarmConfig {
+        websocket: false,
+        quic: false,
+        ..Default::default()
+    }
+}
+
+fn websocket_only() -> SwarmConfig {
+    SwarmConfig {
+        tcp: false,
+        quic: false,
+        ..Default::default()
+    }
+}
+
+fn quic_only() -> SwarmConfig {
+    SwarmConfig {
+        tcp: false,
+        websocket: false,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn tcp() {
+    connect(tcp_only(), false, "/ip4/127.0.0.1/tcp/0").await;
+}
+
+#[tokio::test]
+async fn tcp_with_relay_client() {
+    connect(tcp_only(), true, "/ip4/127.0.0.1/tcp/0").await;
+}
+
+#[tokio::test]
+async fn websocket() {
+    connect(websocket_only(), false, "/ip4/127.0.0.1/tcp/0/ws").await;
+}
+
+#[tokio::test]
+async fn websocket_with_relay_client() {
+    connect(websocket_only(), true, "/ip4/127.0.0.1/tcp/0/ws").await;
+}
+
+#[tokio::test]
+async fn quic() {
+    connect(quic_only(), false, "/ip4/127.0.0.1/udp/0/quic-v1").await;
+}
+
+#[tokio::test]
+async fn quic_with_relay_client() {
+    connect(quic_only(), true, "/ip4/127.0.0.1/udp/0/quic-v1").await;
+}